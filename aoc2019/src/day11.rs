@@ -1,3 +1,4 @@
+use crate::intcode::io::{IoDevice, WouldBlock};
 use crate::intcode::Machine;
 use std::collections::HashMap;
 
@@ -12,23 +13,16 @@ static DIRECTIONS: &[Vector] = &[
     Vector::new(-1, 0),
 ];
 
-struct Paintbot {
-    machine: Machine,
+// The robot's body, which the program drives through its I/O.
+struct Robot {
     position: Point,
     orientation: usize,
     painted: HashMap<Point, isize>,
+    // Outputs alternate between a colour to paint and a direction to turn.
+    turning: bool,
 }
 
-impl Paintbot {
-    fn from_program(src: &str) -> Self {
-        Paintbot {
-            machine: Machine::from_mem_spec(src),
-            painted: HashMap::new(),
-            position: Point::new(0, 0),
-            orientation: 0,
-        }
-    }
-
+impl Robot {
     fn camera_colour(&self) -> isize {
         self.painted.get(&self.position).copied().unwrap_or(0)
     }
@@ -46,36 +40,60 @@ impl Paintbot {
     fn move_forward(&mut self) {
         self.position += DIRECTIONS[self.orientation];
     }
+}
 
-    fn run(&mut self) {
-        use crate::intcode::Step::*;
-        loop {
-            match self.machine.step() {
-                Continue => continue,
-                Halt => break,
-                Input => self.machine.input.push_back(self.camera_colour()),
-                Output(color) => {
-                    self.painted.insert(self.position, color);
-                    let turn = self.machine.run_to_output().unwrap();
-                    match turn {
-                        0 => self.turn_left(),
-                        1 => self.turn_right(),
-                        _ => panic!("unexpected output for turning!"),
-                    }
-                    self.move_forward();
-                }
+impl IoDevice for Robot {
+    fn read(&mut self) -> Result<isize, WouldBlock> {
+        Ok(self.camera_colour())
+    }
+
+    fn write(&mut self, value: isize) {
+        if self.turning {
+            match value {
+                0 => self.turn_left(),
+                1 => self.turn_right(),
+                _ => panic!("unexpected output for turning!"),
             }
+            self.move_forward();
+        } else {
+            self.painted.insert(self.position, value);
         }
+        self.turning = !self.turning;
+    }
+}
+
+struct Paintbot {
+    machine: Machine,
+    robot: Robot,
+}
+
+impl Paintbot {
+    fn from_program(src: &str) -> Self {
+        Paintbot {
+            machine: Machine::from_mem_spec(src).unwrap(),
+            robot: Robot {
+                painted: HashMap::new(),
+                position: Point::new(0, 0),
+                orientation: 0,
+                turning: false,
+            },
+        }
+    }
+
+    fn run(&mut self) {
+        // The camera always has something to say, so this only stops at a halt.
+        self.machine.run_with(&mut self.robot).unwrap();
     }
 
     fn render(&self) -> String {
-        let bounds = BoundingBox::from_points(self.painted.keys());
+        let painted = &self.robot.painted;
+        let bounds = BoundingBox::from_points(painted.keys());
         let xs = (bounds.min.x)..=(bounds.max.x);
         let ys = (bounds.min.y)..=(bounds.max.y);
         let mut out = String::new();
         for y in ys {
             for x in xs.clone() {
-                match self.painted.get(&Point::new(x, y)).copied().unwrap_or(0) {
+                match painted.get(&Point::new(x, y)).copied().unwrap_or(0) {
                     0 => out.push('.'),
                     1 => out.push('#'),
                     _ => panic!("unexpected paint colour!"),
@@ -91,13 +109,13 @@ impl Paintbot {
 fn problem_1() {
     let mut paintbot = Paintbot::from_program(INPUT);
     paintbot.run();
-    assert_eq!(paintbot.painted.values().count(), 1747);
+    assert_eq!(paintbot.robot.painted.values().count(), 1747);
 }
 
 #[test]
 fn problem_2() {
     let mut paintbot = Paintbot::from_program(INPUT);
-    paintbot.painted.insert(Point::new(0, 0), 1);
+    paintbot.robot.painted.insert(Point::new(0, 0), 1);
     paintbot.run();
     let target = ".####..##...##..###..#..#.#..#.#....###....
 ....#.#..#.#..#.#..#.#..#.#.#..#....#..#...
@@ -1,58 +1,109 @@
+use crate::intcode::isa::InstructionSet;
+use crate::intcode::symbolic::Symbolic;
 use crate::intcode::Machine;
 
 #[test]
 fn problem_1_examples() {
-    let mut machine = Machine::from_mem_spec("1,9,10,3,2,3,11,0,99,30,40,50");
-    machine.run();
+    let mut machine = Machine::from_mem_spec("1,9,10,3,2,3,11,0,99,30,40,50").unwrap();
+    machine.run().unwrap();
     assert_eq!(
         &machine.memory,
         &[3500, 9, 10, 70, 2, 3, 11, 0, 99, 30, 40, 50]
     );
 
-    let mut machine = Machine::from_mem_spec("1,0,0,0,99");
-    machine.run();
+    let mut machine = Machine::from_mem_spec("1,0,0,0,99").unwrap();
+    machine.run().unwrap();
     assert_eq!(&machine.memory, &[2, 0, 0, 0, 99]);
 
-    let mut machine = Machine::from_mem_spec("2,3,0,3,99");
-    machine.run();
+    let mut machine = Machine::from_mem_spec("2,3,0,3,99").unwrap();
+    machine.run().unwrap();
     assert_eq!(&machine.memory, &[2, 3, 0, 6, 99]);
 
-    let mut machine = Machine::from_mem_spec("2,4,4,5,99,0");
-    machine.run();
+    let mut machine = Machine::from_mem_spec("2,4,4,5,99,0").unwrap();
+    machine.run().unwrap();
     assert_eq!(&machine.memory, &[2, 4, 4, 5, 99, 9801]);
 
-    let mut machine = Machine::from_mem_spec("1,1,1,4,99,5,6,0,99");
-    machine.run();
+    let mut machine = Machine::from_mem_spec("1,1,1,4,99,5,6,0,99").unwrap();
+    machine.run().unwrap();
     assert_eq!(&machine.memory, &[30, 1, 1, 4, 2, 5, 6, 0, 99]);
 }
 
 #[test]
 fn problem_1() {
-    let mut machine = Machine::from_mem_spec(INPUT);
+    let mut machine = Machine::from_mem_spec(INPUT).unwrap();
+    machine.set_instructions(InstructionSet::day2());
     machine.memory[1] = 12;
     machine.memory[2] = 2;
-    machine.run();
+    machine.run().unwrap();
 
     assert_eq!(machine.memory[0], 4930687);
 }
 
+// Runs the program once with the noun and verb left as symbols, then
+// solves the resulting expression for them.
+fn find_noun_verb(target: isize) -> Option<isize> {
+    let program = Machine::from_mem_spec(INPUT).unwrap();
+    let mut symbolic = Symbolic::new(&program.memory.to_vec());
+    symbolic.symbol_at(1, "noun").ok()?;
+    symbolic.symbol_at(2, "verb").ok()?;
+    symbolic.run().ok()?;
+    let solution = symbolic.solve(&symbolic.memory[0], target, &[0..=99, 0..=99])?;
+    Some(100 * solution[0] + solution[1])
+}
+
 #[test]
 fn problem_2() {
-    let mut result = None;
-    'done: for noun in 0..=99 {
+    assert_eq!(find_noun_verb(19690720), Some(5335));
+}
+
+#[test]
+fn problem_2_agrees_with_problem_1() {
+    let program = Machine::from_mem_spec(INPUT).unwrap();
+    let mut symbolic = Symbolic::new(&program.memory.to_vec());
+    symbolic.symbol_at(1, "noun").unwrap();
+    symbolic.symbol_at(2, "verb").unwrap();
+    symbolic.run().unwrap();
+    assert_eq!(symbolic.memory[0].eval(&[12, 2]), Some(4930687));
+}
+
+// The brute force the symbolic solver replaced. It's still the best
+// workout for forking lots of short-lived machines.
+#[cfg(test)]
+fn find_noun_verb_by_forking(target: isize) -> Option<isize> {
+    let program = Machine::from_mem_spec(INPUT).unwrap();
+    for noun in 0..=99 {
         for verb in 0..=99 {
-            let mut machine = Machine::from_mem_spec(INPUT);
+            let mut machine = program.clone();
             machine.memory[1] = noun;
             machine.memory[2] = verb;
-            machine.run();
-            if machine.memory[0] == 19690720 {
-                result = Some(100 * noun + verb);
-                break 'done;
+            machine.run().unwrap();
+            if machine.memory[0] == target {
+                return Some(100 * noun + verb);
             }
         }
     }
+    None
+}
 
-    assert_eq!(result, Some(5335));
+#[test]
+fn problem_2_by_forking() {
+    assert_eq!(find_noun_verb_by_forking(19690720), Some(5335));
+}
+
+#[test]
+#[ignore]
+fn bench_problem_2() {
+    crate::utils::bench("day 2 noun/verb search", 20, || {
+        assert_eq!(find_noun_verb(19690720), Some(5335));
+    });
+}
+
+#[test]
+#[ignore]
+fn bench_problem_2_by_forking() {
+    crate::utils::bench("day 2 noun/verb brute force", 20, || {
+        assert_eq!(find_noun_verb_by_forking(19690720), Some(5335));
+    });
 }
 
 static INPUT: &str="1,0,0,3,1,1,2,3,1,3,4,3,1,5,0,3,2,13,1,19,1,10,19,23,1,23,9,27,1,5,27,31,2,31,13,35,1,35,5,39,1,39,5,43,2,13,43,47,2,47,10,51,1,51,6,55,2,55,9,59,1,59,5,63,1,63,13,67,2,67,6,71,1,71,5,75,1,75,5,79,1,79,9,83,1,10,83,87,1,87,10,91,1,91,9,95,1,10,95,99,1,10,99,103,2,103,10,107,1,107,9,111,2,6,111,115,1,5,115,119,2,119,13,123,1,6,123,127,2,9,127,131,1,131,5,135,1,135,13,139,1,139,10,143,1,2,143,147,1,147,10,0,99,2,0,14,0";
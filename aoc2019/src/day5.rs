@@ -1,60 +1,77 @@
+use crate::intcode::coverage::Coverage;
 use crate::intcode::Machine;
 
 #[test]
 fn problem_1() {
-    let mut machine = Machine::from_mem_spec(INPUT);
+    let mut machine = Machine::from_mem_spec(INPUT).unwrap();
     machine.input.push_front(1);
-    machine.run();
+    machine.run().unwrap();
     dbg!(&machine.output);
     assert_eq!(machine.output.pop(), Some(7839346));
 }
 
 #[test]
 fn problem_2_examples() {
-    fn test_io(memory: &str, input: isize, output: isize) {
-        let mut machine = Machine::from_mem_spec(memory);
-        machine.input.push_back(input);
-        machine.run();
-        assert_eq!(machine.output.pop(), Some(output));
+    // As well as checking the answers, make sure each program's examples
+    // between them run all of its code and take every branch both ways.
+    fn test_io(memory: &str, examples: &[(isize, isize)]) {
+        let program = Machine::from_mem_spec(memory).unwrap();
+        let mut coverage = Coverage::new(&program.memory.to_vec());
+        for &(input, output) in examples {
+            let mut machine = program.clone();
+            machine.input.push_back(input);
+            coverage.run(&mut machine).unwrap();
+            assert_eq!(machine.output.pop(), Some(output));
+        }
+        let report = coverage.report();
+        assert!(coverage.never_executed().is_empty(), "{}", report);
+        assert!(coverage.partial_branches().is_empty(), "{}", report);
     }
 
     let eq8p = "3,9,8,9,10,9,4,9,99,-1,8";
-    test_io(eq8p, 8, 1);
-    test_io(eq8p, 9, 0);
+    test_io(eq8p, &[(8, 1), (9, 0)]);
 
     let eq8i = "3,3,1108,-1,8,3,4,3,99";
-    test_io(eq8i, 8, 1);
-    test_io(eq8i, 7, 0);
+    test_io(eq8i, &[(8, 1), (7, 0)]);
 
     let lt8p = "3,9,7,9,10,9,4,9,99,-1,8";
-    test_io(lt8p, 8, 0);
-    test_io(lt8p, 7, 1);
-    test_io(lt8p, -7, 1);
+    test_io(lt8p, &[(8, 0), (7, 1), (-7, 1)]);
 
     let lt8i = "3,3,1107,-1,8,3,4,3,99";
-    test_io(lt8i, 8, 0);
-    test_io(lt8i, 7, 1);
-    test_io(lt8i, -7, 1);
+    test_io(lt8i, &[(8, 0), (7, 1), (-7, 1)]);
 
     let jmp_eq0p = "3,12,6,12,15,1,13,14,13,4,13,99,-1,0,1,9";
-    test_io(jmp_eq0p, 0, 0);
-    test_io(jmp_eq0p, 7, 1);
+    test_io(jmp_eq0p, &[(0, 0), (7, 1)]);
 
     let jmp_eq0i = "3,3,1105,-1,9,1101,0,0,12,4,12,99,1";
-    test_io(jmp_eq0i, 0, 0);
-    test_io(jmp_eq0i, 7, 1);
+    test_io(jmp_eq0i, &[(0, 0), (7, 1)]);
 
-    let big_test = "3,21,1008,21,8,20,1005,20,22,107,8,21,20,1006,20,31,1106,0,36,98,0,0,1002,21,125,20,4,20,1105,1,46,104,999,1105,1,46,1101,1000,1,20,4,20,1105,1,46,98,99";
-    test_io(big_test, 3, 999);
-    test_io(big_test, 8, 1000);
-    test_io(big_test, 10, 1001);
+    test_io(crate::intcode::BIG_TEST, &[(3, 999), (8, 1000), (10, 1001)]);
+}
+
+#[test]
+fn diagnostic_modifies_itself() {
+    let mut machine = Machine::from_mem_spec(INPUT).unwrap();
+    machine.detect_self_modification();
+    machine.input.push_front(5);
+    machine.run().unwrap();
+    // It reuses its first instruction as scratch space once it's done with it.
+    let patches = machine.self_modification.unwrap().patches;
+    let patches: Vec<String> = patches.iter().map(|patch| patch.to_string()).collect();
+    assert_eq!(
+        patches,
+        vec![
+            "0284: patched [0] 3->294, the opcode at 0000",
+            "0304: patched [0] 294->314, the opcode at 0000",
+        ]
+    );
 }
 
 #[test]
 fn problem_2() {
-    let mut machine = Machine::from_mem_spec(INPUT);
+    let mut machine = Machine::from_mem_spec(INPUT).unwrap();
     machine.input.push_front(5);
-    machine.run();
+    machine.run().unwrap();
     assert_eq!(machine.output.pop(), Some(447803));
 }
 
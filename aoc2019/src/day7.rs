@@ -1,56 +1,46 @@
+use crate::intcode::runtime::{Channel, Outcome, Ports, Scheduler};
 use crate::intcode::Machine;
 use itertools::Itertools;
 
-struct Amp {
-    machine: Machine,
-}
-
-impl Amp {
-    fn new(mut machine: Machine, phase: isize) -> Self {
-        machine.input.push_back(phase);
-        Amp { machine }
-    }
-
-    fn run(&mut self, input: isize) -> Option<isize> {
-        self.machine.input.push_back(input);
-        self.machine.run_to_output()
-    }
-}
-
 struct AmpChain {
-    amps: Vec<Amp>,
+    scheduler: Scheduler,
+    first: Channel,
+    last: Channel,
 }
 
 impl AmpChain {
-    fn from_phases(machine: &Machine, phases: impl Iterator<Item = isize>) -> Self {
-        let amps = phases
-            .map(|phase| Amp::new(machine.clone(), phase))
-            .collect();
-        AmpChain { amps }
-    }
-
-    fn get_signal(&mut self) -> isize {
-        self.loop_once(0).unwrap()
-    }
-
-    fn loop_once(&mut self, input: isize) -> Option<isize> {
-        let mut signal = input;
-        for amp in self.amps.iter_mut() {
-            if let Some(new_signal) = amp.run(signal) {
-                signal = new_signal
+    // With `feedback`, the last amp's output goes back into the first.
+    fn from_phases(machine: &Machine, phases: impl Iterator<Item = isize>, feedback: bool) -> Self {
+        let mut scheduler = Scheduler::new();
+        let first = Channel::new();
+        let mut input = first.clone();
+        let mut phases = phases.peekable();
+        while let Some(phase) = phases.next() {
+            input.send(phase);
+            let output = if feedback && phases.peek().is_none() {
+                first.clone()
             } else {
-                return None;
-            }
+                Channel::new()
+            };
+            let ports = Ports {
+                input,
+                output: output.clone(),
+            };
+            scheduler.spawn(machine.clone(), ports);
+            input = output;
+        }
+        AmpChain {
+            scheduler,
+            first,
+            last: input,
         }
-        Some(signal)
     }
 
-    fn get_loop_signal(&mut self) -> isize {
-        let mut signal = 0;
-        while let Some(new_signal) = self.loop_once(signal) {
-            signal = new_signal;
-        }
-        signal
+    fn get_signal(&mut self) -> isize {
+        self.first.send(0);
+        assert_eq!(self.scheduler.run().unwrap(), Outcome::Halted);
+        // With feedback, this is the one signal the first amp never read.
+        self.last.recv().unwrap()
     }
 }
 
@@ -58,7 +48,7 @@ struct Problem(Machine);
 
 impl Problem {
     fn from_program(spec: &str) -> Self {
-        Problem(Machine::from_mem_spec(spec))
+        Problem(Machine::from_mem_spec(spec).unwrap())
     }
 
     fn max_signal(&self) -> isize {
@@ -67,7 +57,9 @@ impl Problem {
         phases
             .iter()
             .permutations(5)
-            .map(|perm| AmpChain::from_phases(machine, perm.into_iter().copied()).get_signal())
+            .map(|perm| {
+                AmpChain::from_phases(machine, perm.into_iter().copied(), false).get_signal()
+            })
             .max()
             .unwrap()
     }
@@ -78,7 +70,9 @@ impl Problem {
         phases
             .iter()
             .permutations(5)
-            .map(|perm| AmpChain::from_phases(machine, perm.into_iter().copied()).get_loop_signal())
+            .map(|perm| {
+                AmpChain::from_phases(machine, perm.into_iter().copied(), true).get_signal()
+            })
             .max()
             .unwrap()
     }
@@ -121,4 +115,26 @@ fn problem_2() {
     assert_eq!(Problem::from_program(INPUT).max_loop_signal(), 19384820);
 }
 
+#[test]
+#[ignore]
+fn bench_permutation_search() {
+    let problem = Problem::from_program(INPUT);
+    crate::utils::bench("day 7 permutation search", 20, || {
+        assert_eq!(problem.max_signal(), 17790);
+        assert_eq!(problem.max_loop_signal(), 19384820);
+    });
+}
+
+// Just the forking, without running anything.
+#[test]
+#[ignore]
+fn bench_amp_chains() {
+    let machine = Machine::from_mem_spec(INPUT).unwrap();
+    crate::utils::bench("day 7 amp chains", 20, || {
+        for perm in (5..10).permutations(5) {
+            AmpChain::from_phases(&machine, perm.into_iter(), true);
+        }
+    });
+}
+
 static INPUT: &str = "3,8,1001,8,10,8,105,1,0,0,21,38,63,72,85,110,191,272,353,434,99999,3,9,102,4,9,9,101,2,9,9,102,3,9,9,4,9,99,3,9,1001,9,4,9,102,2,9,9,1001,9,5,9,1002,9,5,9,101,3,9,9,4,9,99,3,9,1001,9,2,9,4,9,99,3,9,1001,9,3,9,102,2,9,9,4,9,99,3,9,101,2,9,9,102,2,9,9,1001,9,2,9,1002,9,4,9,101,2,9,9,4,9,99,3,9,1002,9,2,9,4,9,3,9,102,2,9,9,4,9,3,9,101,2,9,9,4,9,3,9,101,2,9,9,4,9,3,9,101,1,9,9,4,9,3,9,101,2,9,9,4,9,3,9,1002,9,2,9,4,9,3,9,101,2,9,9,4,9,3,9,101,1,9,9,4,9,3,9,1002,9,2,9,4,9,99,3,9,1001,9,1,9,4,9,3,9,102,2,9,9,4,9,3,9,1001,9,2,9,4,9,3,9,102,2,9,9,4,9,3,9,1001,9,2,9,4,9,3,9,101,2,9,9,4,9,3,9,1002,9,2,9,4,9,3,9,1001,9,2,9,4,9,3,9,1001,9,2,9,4,9,3,9,1001,9,1,9,4,9,99,3,9,1001,9,1,9,4,9,3,9,1001,9,1,9,4,9,3,9,1001,9,2,9,4,9,3,9,102,2,9,9,4,9,3,9,1002,9,2,9,4,9,3,9,102,2,9,9,4,9,3,9,1002,9,2,9,4,9,3,9,1002,9,2,9,4,9,3,9,101,2,9,9,4,9,3,9,101,2,9,9,4,9,99,3,9,1001,9,2,9,4,9,3,9,1002,9,2,9,4,9,3,9,101,1,9,9,4,9,3,9,102,2,9,9,4,9,3,9,1001,9,2,9,4,9,3,9,1002,9,2,9,4,9,3,9,102,2,9,9,4,9,3,9,1001,9,2,9,4,9,3,9,1002,9,2,9,4,9,3,9,102,2,9,9,4,9,99,3,9,1002,9,2,9,4,9,3,9,101,1,9,9,4,9,3,9,101,2,9,9,4,9,3,9,101,1,9,9,4,9,3,9,101,2,9,9,4,9,3,9,102,2,9,9,4,9,3,9,101,2,9,9,4,9,3,9,1002,9,2,9,4,9,3,9,1002,9,2,9,4,9,3,9,101,2,9,9,4,9,99";
@@ -1,280 +1,887 @@
-use lazy_static::lazy_static;
-use std::collections::{HashMap, VecDeque};
+use derive_more::Display;
+use io::{IoDevice, Queues};
+use isa::InstructionSet;
+use memory::{Memory, Paged, PAGE_SIZE};
+use profile::Profile;
+use selfmod::SelfModification;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use trace::Trace;
+use watch::{Access, Watchpoints};
+use word::Word;
 
+pub mod ascii;
+pub mod asm;
+pub mod cfg;
+pub mod compile;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod io;
+pub mod isa;
+pub mod load;
+pub mod loops;
+pub mod memory;
+pub mod network;
+pub mod profile;
+pub mod runtime;
+pub mod selfmod;
+pub mod snapshot;
+pub mod symbolic;
+pub mod trace;
+pub mod watch;
+pub mod word;
+
+// Memory is sparse, so this is about catching runaway programs rather than
+// saving space.
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 32;
+
+// What `add` and `mul` do when the result doesn't fit in a word.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Arithmetic {
+    Wrapping,
+    // Faults with `MachineError::Overflow` instead.
+    Checked,
+}
+
+// Generic over the word type for programs that outgrow `isize`, see `Word`.
 #[derive(Debug, Clone)]
-pub struct Machine {
+pub struct Machine<W: Word = isize> {
     pub pc: usize,
     pub relative_base: isize,
-    pub memory: Vec<isize>,
-    // Specifying the input events ahead of time might come back to bite us
-    // but it's good enough for now.
-    // It's hard to predict how we'll need to change this for "interactive"
-    // problems.
+    pub memory: Memory<W>,
+    // Specifying the input events ahead of time is good enough for most
+    // problems. Interactive ones can plug in an `IoDevice` instead, see
+    // `step_with` and `run_with`.
     //
     // This is a vecdeque solely so we can pop from the front.
-    pub input: VecDeque<isize>,
-    pub output: Vec<isize>,
+    pub input: VecDeque<W>,
+    pub output: Vec<W>,
+    // Set this to start recording every instruction executed.
+    pub trace: Option<Trace<W>>,
+    // Set this to count what gets executed, see `profile::Profile::report`.
+    pub profile: Option<Profile>,
+    // Set this (or call `watch`) to collect accesses to watched addresses.
+    pub watchpoints: Option<Watchpoints<W>>,
+    // Set this (or call `detect_self_modification`) to catch the program
+    // writing over instructions it has already run.
+    pub self_modification: Option<SelfModification<W>>,
+    pub arithmetic: Arithmetic,
+    // Touching any address at or past this is a fault.
+    pub memory_limit: usize,
+    // Shared, since forks hardly ever want a different one.
+    instructions: Arc<InstructionSet<W>>,
+    // Indexed by address, and filled in for the whole program up front so
+    // that forks share it rather than each decoding everything again. Each
+    // entry remembers the code it came from, so a write that changes the code
+    // invalidates it without the cache having to be touched.
+    decoded: Arc<Paged<Option<Decoded<W>>>>,
 }
 
+// An instruction's opcode and modes, so hot loops don't have to keep
+// re-parsing them.
+#[derive(Debug)]
+struct Decoded<W: Word> {
+    code: isize,
+    opcode: Opcode,
+    modes: Modes,
+    instruction: InstructionImpl<W>,
+}
+
+// Deriving these would require `W: Copy`, which `BigInt` isn't.
+impl<W: Word> Clone for Decoded<W> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W: Word> Copy for Decoded<W> {}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub enum Step {
+pub enum Step<W = isize> {
     Continue,
     Input,
-    Output(isize),
+    Output(W),
     Halt,
 }
 
+// How a run that can give up early ended. Giving up leaves the machine
+// ready to carry on from `pc`.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-enum ArgMode {
+pub enum Stop {
+    Halted,
+    OutOfSteps { pc: usize },
+    TimedOut { pc: usize },
+    // See `loops::LoopDetector`.
+    Looped { pc: usize },
+    // Asked for by a watchpoint callback, see `Machine::run_watching`.
+    Paused { pc: usize },
+}
+
+// Looking at the clock every step would dominate the run time.
+const STEPS_PER_CLOCK_CHECK: usize = 1024;
+
+// Everything that can go wrong while loading or running a program.
+// `pc` is always the address of the instruction that faulted.
+#[derive(Debug, Display, PartialEq, Eq, Clone)]
+pub enum MachineError {
+    #[display(fmt = "unknown opcode {} at pc {}", code, pc)]
+    UnknownOpcode { pc: usize, code: isize },
+    #[display(
+        fmt = "invalid argument mode {} in opcode {} at pc {}",
+        digit,
+        code,
+        pc
+    )]
+    InvalidMode {
+        pc: usize,
+        code: isize,
+        digit: isize,
+    },
+    #[display(fmt = "write in immediate mode at pc {}", pc)]
+    ImmediateWrite { pc: usize },
+    #[display(fmt = "negative address {} at pc {}", addr, pc)]
+    NegativeAddress { pc: usize, addr: isize },
+    #[display(
+        fmt = "address {} is past the memory limit of {} at pc {}",
+        addr,
+        limit,
+        pc
+    )]
+    MemoryLimit {
+        pc: usize,
+        addr: usize,
+        limit: usize,
+    },
+    #[display(fmt = "arithmetic overflow at pc {}", pc)]
+    Overflow { pc: usize },
+    #[display(fmt = "waiting for input at pc {}", pc)]
+    MissingInput { pc: usize },
+    // Whatever went wrong in an opcode registered with `InstructionSet::register`.
+    #[display(fmt = "{} failed at pc {}: {}", mnemonic, pc, reason)]
+    Custom {
+        pc: usize,
+        mnemonic: &'static str,
+        reason: &'static str,
+    },
+    #[display(fmt = "could not parse {:?} at offset {}", token, offset)]
+    Parse { offset: usize, token: String },
+}
+
+impl std::error::Error for MachineError {}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ArgMode {
     Immediate,
     Position,
     Relative,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
-struct Opcode(usize);
-
-type InstructionImpl = fn(&mut Machine, Vec<ArgMode>) -> Step;
-
-lazy_static! {
-    static ref INSTRUCTION: HashMap<Opcode, InstructionImpl> = {
-        let mut m = HashMap::new();
-        m.insert(Opcode(1), Machine::add as InstructionImpl);
-        m.insert(Opcode(2), Machine::mul);
-        m.insert(Opcode(3), Machine::input);
-        m.insert(Opcode(4), Machine::output);
-        m.insert(Opcode(5), Machine::jump_if_true);
-        m.insert(Opcode(6), Machine::jump_if_false);
-        m.insert(Opcode(7), Machine::less_than);
-        m.insert(Opcode(8), Machine::equals);
-        m.insert(Opcode(9), Machine::adjust_relative_base);
-        m.insert(Opcode(99), Machine::halt);
-        m
-    };
+pub struct Opcode(pub usize);
+
+impl Opcode {
+    // Mnemonic, argument count, and which argument (if any) gets written to,
+    // for the full instruction set. Machines can be given a different one,
+    // see `isa::InstructionSet`.
+    pub fn info(self) -> Option<(&'static str, usize, Option<usize>)> {
+        Some(match self.0 {
+            1 => ("ADD", 3, Some(2)),
+            2 => ("MUL", 3, Some(2)),
+            3 => ("IN", 1, Some(0)),
+            4 => ("OUT", 1, None),
+            5 => ("JT", 2, None),
+            6 => ("JF", 2, None),
+            7 => ("LT", 3, Some(2)),
+            8 => ("EQ", 3, Some(2)),
+            9 => ("ARB", 1, None),
+            99 => ("HLT", 0, None),
+            _ => return None,
+        })
+    }
 }
 
+type Modes = [ArgMode; 3];
+
+type InstructionImpl<W> =
+    fn(&mut Machine<W>, &mut dyn IoDevice<W>, Modes) -> Result<Step<W>, MachineError>;
+
 // We could do something fancy and only return as many arg modes as
 // are needed for the given opcode, or we could be lazy and just return
 // the maximum number needed.
-fn parse_opcode(code: isize) -> (Opcode, Vec<ArgMode>) {
+fn parse_opcode(pc: usize, code: isize) -> Result<(Opcode, Modes), MachineError> {
     use ArgMode::*;
-    assert!(code >= 0);
+    if code < 0 {
+        return Err(MachineError::UnknownOpcode { pc, code });
+    }
     let opcode = Opcode((code % 100) as usize); // get last two digits
     let mut mode = code / 100;
-    let mut result = Vec::new();
+    let mut result = [Position; 3];
+    let mut i = 0;
+    // Any digits past the third are still checked, but otherwise ignored.
     while mode > 0 {
-        result.push(match mode % 10 {
+        let arg_mode = match mode % 10 {
             0 => Position,
             1 => Immediate,
             2 => Relative,
-            other => panic!("Unexpected opcode argument mode {}", other),
-        });
+            digit => return Err(MachineError::InvalidMode { pc, code, digit }),
+        };
+        if i < 3 {
+            result[i] = arg_mode;
+        }
+        i += 1;
         mode /= 10;
     }
-    result.resize(3, Position);
-    (opcode, result)
+    Ok((opcode, result))
 }
 
 #[test]
 fn check_parse_opcode() {
     use ArgMode::*;
     assert_eq!(
-        parse_opcode(3),
-        (Opcode(3), vec![Position, Position, Position])
+        parse_opcode(0, 3),
+        Ok((Opcode(3), [Position, Position, Position]))
+    );
+    assert_eq!(
+        parse_opcode(0, 1002),
+        Ok((Opcode(2), [Position, Immediate, Position]))
+    );
+    assert_eq!(
+        parse_opcode(0, 144),
+        Ok((Opcode(44), [Immediate, Position, Position]))
     );
     assert_eq!(
-        parse_opcode(1002),
-        (Opcode(2), vec![Position, Immediate, Position])
+        parse_opcode(0, 20200),
+        Ok((Opcode(0), [Relative, Position, Relative]))
     );
     assert_eq!(
-        parse_opcode(144),
-        (Opcode(44), vec![Immediate, Position, Position])
+        parse_opcode(7, 1301),
+        Err(MachineError::InvalidMode {
+            pc: 7,
+            code: 1301,
+            digit: 3
+        })
     );
     assert_eq!(
-        parse_opcode(20200),
-        (Opcode(0), vec![Relative, Position, Relative])
+        parse_opcode(7, -1),
+        Err(MachineError::UnknownOpcode { pc: 7, code: -1 })
     );
 }
 
 impl Machine {
-    pub fn from_mem_spec(mem: &str) -> Self {
-        let memory: Vec<isize> = mem.split(',').map(|s| s.parse().unwrap()).collect();
-        Machine {
+    pub fn from_mem_spec(mem: &str) -> Result<Self, MachineError> {
+        Machine::parse(mem)
+    }
+
+    pub fn from_words(memory: Vec<isize>) -> Self {
+        Machine::with_words(memory)
+    }
+}
+
+impl<W: Word> Machine<W> {
+    // `from_mem_spec` for other word types, e.g. `Machine::<BigInt>::parse`.
+    pub fn parse(mem: &str) -> Result<Self, MachineError> {
+        let mut memory = Vec::new();
+        let mut offset = 0;
+        for token in mem.split(',') {
+            let value = token.parse().map_err(|_| MachineError::Parse {
+                offset,
+                token: token.to_string(),
+            })?;
+            memory.push(value);
+            // Skip past the token and its trailing comma.
+            offset += token.len() + 1;
+        }
+        Ok(Machine::with_words(memory))
+    }
+
+    pub fn with_words(memory: Vec<W>) -> Self {
+        let mut machine = Machine {
             pc: 0,
             relative_base: 0,
             input: VecDeque::new(),
             output: Vec::new(),
-            memory,
+            memory: Memory::from(memory),
+            trace: None,
+            profile: None,
+            watchpoints: None,
+            self_modification: None,
+            arithmetic: Arithmetic::Wrapping,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            instructions: Arc::new(InstructionSet::full()),
+            decoded: Arc::new(Paged::new()),
+        };
+        machine.decode_all();
+        machine
+    }
+
+    pub fn instructions(&self) -> &InstructionSet<W> {
+        &self.instructions
+    }
+
+    pub fn set_instructions(&mut self, instructions: InstructionSet<W>) {
+        self.instructions = Arc::new(instructions);
+        // Anything decoded under the old rules might not mean the same now.
+        self.decode_all();
+    }
+
+    fn grow_mem(&mut self, addr: usize) -> Result<(), MachineError> {
+        if addr >= self.memory_limit {
+            return Err(MachineError::MemoryLimit {
+                pc: self.pc,
+                addr,
+                limit: self.memory_limit,
+            });
+        }
+        if addr >= self.memory.len() {
+            self.memory.resize(addr + 1);
         }
+        Ok(())
     }
 
-    fn grow_mem(&mut self, addr: usize) {
+    // The word at `addr`, growing memory to reach it if need be.
+    #[inline]
+    fn cell(&mut self, addr: usize) -> Result<&W, MachineError> {
         if addr >= self.memory.len() {
-            self.memory.resize(addr + 1, 0);
+            self.grow_mem(addr)?;
         }
+        Ok(&self.memory[addr])
     }
 
-    fn read(&mut self, addr: usize, mode: ArgMode) -> isize {
-        match mode {
-            ArgMode::Immediate => {
-                self.grow_mem(addr);
-                self.memory[addr]
-            }
-            ArgMode::Position => {
-                let addr = self.memory[addr];
-                debug_assert!(addr >= 0);
-                self.grow_mem(addr as usize);
-                self.memory[addr as usize]
-            }
-            ArgMode::Relative => {
-                let addr = self.memory[addr];
-                let addr = addr as isize + self.relative_base;
-                debug_assert!(addr >= 0);
-                self.grow_mem(addr as usize);
-                self.memory[addr as usize]
-            }
+    fn address(&self, addr: isize) -> Result<usize, MachineError> {
+        if addr < 0 {
+            Err(MachineError::NegativeAddress { pc: self.pc, addr })
+        } else {
+            Ok(addr as usize)
         }
     }
 
-    fn write(&mut self, addr: usize, value: isize, mode: ArgMode) {
+    // Where an argument in position or relative mode points.
+    fn pointer(&mut self, addr: usize, mode: ArgMode) -> Result<usize, MachineError> {
+        let pointer = self.cell(addr)?.saturating_isize();
         match mode {
-            ArgMode::Position => {
-                self.grow_mem(addr);
-                let target = self.memory[addr];
-                debug_assert!(target >= 0);
-                self.grow_mem(target as usize);
-                self.memory[target as usize] = value;
-            }
-            ArgMode::Relative => {
-                self.grow_mem(addr);
-                let addr = self.memory[addr];
-                let addr = addr + self.relative_base;
-                debug_assert!(addr >= 0);
-                self.grow_mem(addr as usize);
-                self.memory[addr as usize] = value;
-            }
-            ArgMode::Immediate => panic!("Writing out in immediate mode!"),
+            ArgMode::Position => self.address(pointer),
+            ArgMode::Relative => self.address(pointer.saturating_add(self.relative_base)),
+            ArgMode::Immediate => Ok(addr),
+        }
+    }
+
+    fn read(&mut self, addr: usize, mode: ArgMode) -> Result<W, MachineError> {
+        let source = self.pointer(addr, mode)?;
+        let value = self.cell(source)?.clone();
+        if let Some(trace) = &mut self.trace {
+            trace.record_read(source, value.clone());
+        }
+        if let Some(watchpoints) = &mut self.watchpoints {
+            watchpoints.record(self.pc, source, Access::Read, &value, &value);
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, addr: usize, value: W, mode: ArgMode) -> Result<(), MachineError> {
+        if mode == ArgMode::Immediate {
+            self.cell(addr)?;
+            return Err(MachineError::ImmediateWrite { pc: self.pc });
+        }
+        let target = self.pointer(addr, mode)?;
+        self.cell(target)?;
+        if let Some(trace) = &mut self.trace {
+            trace.record_write(target, self.memory[target].clone(), value.clone());
+        }
+        if let Some(watchpoints) = &mut self.watchpoints {
+            let old = &self.memory[target];
+            watchpoints.record(self.pc, target, Access::Write, old, &value);
+        }
+        if let Some(self_modification) = &mut self.self_modification {
+            self_modification.record(self.pc, target, &self.memory[target], &value);
         }
+        self.memory[target] = value;
+        Ok(())
     }
 
-    fn add(&mut self, modes: Vec<ArgMode>) -> Step {
+    fn add(&mut self, _: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
         // Could we factor out this common logic?
         // Probably, but then the spec would probably change and we'd be screwed.
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        self.write(self.pc + 3, in1 + in2, modes[2]);
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        let sum = match self.arithmetic {
+            Arithmetic::Wrapping => in1.wrapping_add(&in2),
+            Arithmetic::Checked => in1
+                .checked_add(&in2)
+                .ok_or(MachineError::Overflow { pc: self.pc })?,
+        };
+        self.write(self.pc + 3, sum, modes[2])?;
         self.pc += 4;
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn mul(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        self.write(self.pc + 3, in1 * in2, modes[2]);
+    fn mul(&mut self, _: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        let product = match self.arithmetic {
+            Arithmetic::Wrapping => in1.wrapping_mul(&in2),
+            Arithmetic::Checked => in1
+                .checked_mul(&in2)
+                .ok_or(MachineError::Overflow { pc: self.pc })?,
+        };
+        self.write(self.pc + 3, product, modes[2])?;
         self.pc += 4;
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn halt(&mut self, _: Vec<ArgMode>) -> Step {
-        Step::Halt
+    fn halt(&mut self, _: &mut dyn IoDevice<W>, _: Modes) -> Result<Step<W>, MachineError> {
+        Ok(Step::Halt)
     }
 
-    fn input(&mut self, modes: Vec<ArgMode>) -> Step {
-        if let Some(in1) = self.input.pop_front() {
-            self.write(self.pc + 1, in1, modes[0]);
+    fn input(&mut self, io: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
+        if let Ok(in1) = io.read() {
+            self.write(self.pc + 1, in1, modes[0])?;
             self.pc += 2;
-            Step::Continue
+            Ok(Step::Continue)
         } else {
-            Step::Input
+            Ok(Step::Input)
         }
     }
 
-    fn output(&mut self, modes: Vec<ArgMode>) -> Step {
-        let out1 = self.read(self.pc + 1, modes[0]);
-        self.output.push(out1);
+    fn output(&mut self, io: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
+        let out1 = self.read(self.pc + 1, modes[0])?;
+        io.write(out1.clone());
         self.pc += 2;
-        Step::Output(out1)
+        Ok(Step::Output(out1))
     }
 
-    fn jump_if_true(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        if in1 != 0 {
-            debug_assert!(in2 >= 0);
-            self.pc = in2 as usize;
+    fn jump_if_true(
+        &mut self,
+        _: &mut dyn IoDevice<W>,
+        modes: Modes,
+    ) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        if in1 != W::default() {
+            self.pc = self.address(in2.saturating_isize())?;
         } else {
             self.pc += 3;
         }
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn jump_if_false(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        if in1 == 0 {
-            debug_assert!(in2 >= 0);
-            self.pc = in2 as usize;
+    fn jump_if_false(
+        &mut self,
+        _: &mut dyn IoDevice<W>,
+        modes: Modes,
+    ) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        if in1 == W::default() {
+            self.pc = self.address(in2.saturating_isize())?;
         } else {
             self.pc += 3;
         }
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn less_than(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        let result = if in1 < in2 { 1 } else { 0 };
-        self.write(self.pc + 3, result, modes[2]);
+    fn less_than(
+        &mut self,
+        _: &mut dyn IoDevice<W>,
+        modes: Modes,
+    ) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        let result = W::from_isize(if in1 < in2 { 1 } else { 0 });
+        self.write(self.pc + 3, result, modes[2])?;
         self.pc += 4;
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn equals(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        let in2 = self.read(self.pc + 2, modes[1]);
-        let result = if in1 == in2 { 1 } else { 0 };
-        self.write(self.pc + 3, result, modes[2]);
+    fn equals(&mut self, _: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let in2 = self.read(self.pc + 2, modes[1])?;
+        let result = W::from_isize(if in1 == in2 { 1 } else { 0 });
+        self.write(self.pc + 3, result, modes[2])?;
         self.pc += 4;
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    fn adjust_relative_base(&mut self, modes: Vec<ArgMode>) -> Step {
-        let in1 = self.read(self.pc + 1, modes[0]);
-        self.relative_base += in1;
+    fn adjust_relative_base(
+        &mut self,
+        _: &mut dyn IoDevice<W>,
+        modes: Modes,
+    ) -> Result<Step<W>, MachineError> {
+        let in1 = self.read(self.pc + 1, modes[0])?;
+        let offset = in1.saturating_isize();
+        self.relative_base = match self.arithmetic {
+            Arithmetic::Wrapping => self.relative_base.wrapping_add(offset),
+            Arithmetic::Checked => self
+                .relative_base
+                .checked_add(offset)
+                .ok_or(MachineError::Overflow { pc: self.pc })?,
+        };
         self.pc += 2;
-        Step::Continue
+        Ok(Step::Continue)
     }
 
-    pub fn step(&mut self) -> Step {
-        let code = self.memory[self.pc];
-        let (code, modes) = parse_opcode(code);
-        let instruction = INSTRUCTION[&code];
-        instruction(self, modes)
+    fn decode_code(&self, pc: usize, code: isize) -> Result<Decoded<W>, MachineError> {
+        let (opcode, modes) = parse_opcode(pc, code)?;
+        let instruction = (self.instructions.implementation(opcode))
+            .ok_or(MachineError::UnknownOpcode { pc, code })?;
+        Ok(Decoded {
+            code,
+            opcode,
+            modes,
+            instruction,
+        })
+    }
+
+    // Data gets decoded too, but anything that doesn't make sense as an
+    // instruction is left out. Zero doesn't, so neither do pages of it,
+    // however many there are.
+    fn decode_all(&mut self) {
+        let mut decoded = Paged::new();
+        decoded.resize(self.memory.len());
+        for (page, contents) in self.memory.pages() {
+            if contents.iter().all(|word| *word == W::default()) {
+                continue;
+            }
+            let start = page * PAGE_SIZE;
+            for (pc, word) in (start..self.memory.len()).zip(contents.iter()) {
+                decoded[pc] = self.decode_code(pc, word.saturating_isize()).ok();
+            }
+        }
+        self.decoded = Arc::new(decoded);
+    }
+
+    fn decode(&mut self) -> Result<Decoded<W>, MachineError> {
+        let code = self.cell(self.pc)?.saturating_isize();
+        if let Some(Some(decoded)) = self.decoded.get(self.pc) {
+            if decoded.code == code {
+                return Ok(*decoded);
+            }
+        }
+        let decoded = self.decode_code(self.pc, code)?;
+        // Memory can have grown since `decode_all`, but only as far as the
+        // code goes, since data can be huge.
+        let cache = Arc::make_mut(&mut self.decoded);
+        if self.pc >= cache.len() {
+            cache.resize(self.pc + 1);
+        }
+        cache[self.pc] = Some(decoded);
+        Ok(decoded)
+    }
+
+    // Steps using `self.input` and `self.output` for I/O.
+    pub fn step(&mut self) -> Result<Step<W>, MachineError> {
+        // Shuffling the queues in and out is measurable, so only bother
+        // when the instruction actually does I/O.
+        let decoded = self.decode()?;
+        if !matches!(decoded.opcode, Opcode(3..=4)) {
+            return self.execute(decoded, &mut Queues::default());
+        }
+        let mut queues = Queues {
+            input: std::mem::take(&mut self.input),
+            output: std::mem::take(&mut self.output),
+        };
+        let step = self.execute(decoded, &mut queues);
+        self.input = queues.input;
+        self.output = queues.output;
+        step
+    }
+
+    // Steps, reading and writing through `io` instead of the machine's own
+    // queues. Returns `Step::Input` if the device would block.
+    pub fn step_with(&mut self, io: &mut dyn IoDevice<W>) -> Result<Step<W>, MachineError> {
+        let decoded = self.decode()?;
+        self.execute(decoded, io)
+    }
+
+    fn execute(
+        &mut self,
+        decoded: Decoded<W>,
+        io: &mut dyn IoDevice<W>,
+    ) -> Result<Step<W>, MachineError> {
+        let Decoded {
+            opcode,
+            modes,
+            instruction,
+            ..
+        } = decoded;
+        if let Some(trace) = &mut self.trace {
+            let mnemonic = self
+                .instructions
+                .get(opcode)
+                .map_or("???", |def| def.mnemonic);
+            trace.begin(self.pc, opcode, mnemonic, modes, self.relative_base);
+        }
+        let started = self.profile.as_ref().map(|_| Instant::now());
+        let pc = self.pc;
+        let step = instruction(self, io, modes)?;
+        // Waiting for input doesn't execute anything, so there's nothing to record.
+        if step != Step::Input {
+            if let Some(trace) = &mut self.trace {
+                trace.finish(self.pc, self.relative_base);
+            }
+            if let Some(self_modification) = &mut self.self_modification {
+                let arity = self.instructions.get(opcode).map_or(0, |def| def.arity);
+                self_modification.ran(pc, 1 + arity);
+            }
+        }
+        if let (Some(profile), Some(started)) = (&mut self.profile, started) {
+            if step == Step::Input {
+                profile.blocked += 1;
+            } else {
+                profile.record(pc, opcode, started.elapsed());
+            }
+        }
+        Ok(step)
     }
 
     // Runs to completion, assumes self.input and self.output are set up
     // as needed.
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<(), MachineError> {
         use Step::*;
         loop {
-            match self.step() {
+            match self.step()? {
                 Output(_) | Continue => continue,
-                Halt => break,
-                Input => panic!("waiting for input during complete run!"),
+                Halt => return Ok(()),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
             }
         }
     }
 
     // runs until the next output step, or halts.
-    pub fn run_to_output(&mut self) -> Option<isize> {
+    pub fn run_to_output(&mut self) -> Result<Option<W>, MachineError> {
         use Step::*;
         loop {
-            match self.step() {
+            match self.step()? {
                 Continue => continue,
-                Output(output) => return Some(output),
-                Halt => return None,
-                Input => panic!("waiting for input during output run!"),
+                Output(output) => return Ok(Some(output)),
+                Halt => return Ok(None),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
             }
         }
     }
+
+    // Runs like `run`, but executes at most `max_steps` instructions,
+    // counting the final halt.
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<Stop, MachineError> {
+        use Step::*;
+        for _ in 0..max_steps {
+            match self.step()? {
+                Output(_) | Continue => continue,
+                Halt => return Ok(Stop::Halted),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
+            }
+        }
+        Ok(Stop::OutOfSteps { pc: self.pc })
+    }
+
+    // Runs like `run`, but gives up at `deadline`.
+    pub fn run_until(&mut self, deadline: Instant) -> Result<Stop, MachineError> {
+        use Step::*;
+        for steps in 0.. {
+            if steps % STEPS_PER_CLOCK_CHECK == 0 && Instant::now() >= deadline {
+                break;
+            }
+            match self.step()? {
+                Output(_) | Continue => continue,
+                Halt => return Ok(Stop::Halted),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
+            }
+        }
+        Ok(Stop::TimedOut { pc: self.pc })
+    }
+
+    pub fn run_for(&mut self, timeout: Duration) -> Result<Stop, MachineError> {
+        self.run_until(Instant::now() + timeout)
+    }
+
+    // Runs until the program halts or `io` has no input for it, returning
+    // `Step::Halt` or `Step::Input` respectively.
+    pub fn run_with(&mut self, io: &mut dyn IoDevice<W>) -> Result<Step<W>, MachineError> {
+        use Step::*;
+        loop {
+            match self.step_with(io)? {
+                Output(_) | Continue => continue,
+                step => return Ok(step),
+            }
+        }
+    }
+}
+
+// Day 5's example that outputs 999, 1000 or 1001 as its input is below,
+// equal to, or above 8. It jumps all over the place, so a lot of tests
+// use it.
+#[cfg(test)]
+pub static BIG_TEST: &str = "3,21,1008,21,8,20,1005,20,22,107,8,21,20,1006,20,31,1106,0,36,98,0,0,1002,21,125,20,4,20,1105,1,46,104,999,1105,1,46,1101,1000,1,20,4,20,1105,1,46,98,99";
+
+#[test]
+fn outputs_one_at_a_time() {
+    let mut machine = Machine::from_mem_spec("104,5,3,0,104,6,99").unwrap();
+    machine.input.push_back(4);
+    assert_eq!(machine.run_to_output(), Ok(Some(5)));
+    assert_eq!(machine.run_to_output(), Ok(Some(6)));
+    assert_eq!(machine.run_to_output(), Ok(None));
+    // They still end up in `output` too.
+    assert_eq!(machine.output, [5, 6]);
+
+    let mut machine = Machine::from_mem_spec("3,0,99").unwrap();
+    assert_eq!(
+        machine.run_to_output(),
+        Err(MachineError::MissingInput { pc: 0 })
+    );
+}
+
+#[test]
+fn faults_are_reported() {
+    let fault = |program: &str| Machine::from_mem_spec(program).unwrap().run().unwrap_err();
+
+    assert_eq!(
+        fault("1,0,0,0,42"),
+        MachineError::UnknownOpcode { pc: 4, code: 42 }
+    );
+    assert_eq!(
+        fault("1,0,0,0,1401,0,0,0"),
+        MachineError::InvalidMode {
+            pc: 4,
+            code: 1401,
+            digit: 4
+        }
+    );
+    assert_eq!(
+        fault("11101,1,1,0,99"),
+        MachineError::ImmediateWrite { pc: 0 }
+    );
+    assert_eq!(
+        fault("1,-3,0,0,99"),
+        MachineError::NegativeAddress { pc: 0, addr: -3 }
+    );
+    assert_eq!(fault("3,0,99"), MachineError::MissingInput { pc: 0 });
+    assert_eq!(
+        Machine::from_mem_spec("1,2,x3,4").unwrap_err(),
+        MachineError::Parse {
+            offset: 4,
+            token: "x3".to_string()
+        }
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_dispatch() {
+    let program = asm::assemble(
+        "
+        loop:
+            add [n], #-1 -> [n]
+            jt [n], #loop
+            hlt
+        n: .data 1000000
+        ",
+    )
+    .unwrap();
+    let machine = Machine::from_mem_spec(&program).unwrap();
+    crate::utils::bench("2 million instructions", 10, || {
+        machine.clone().run().unwrap();
+    });
+}
+
+#[test]
+fn forks_share_untouched_memory() {
+    // Reads a value and stores it far away from the program.
+    let mut machine = Machine::from_mem_spec("3,100000,99").unwrap();
+    machine.memory.resize(100_001);
+    let mut fork = machine.clone();
+    machine.input.push_back(1);
+    fork.input.push_back(2);
+    machine.run().unwrap();
+    fork.run().unwrap();
+    assert_eq!(machine.memory[100_000], 1);
+    assert_eq!(fork.memory[100_000], 2);
+    // Only the page each of them wrote to got copied.
+    let pages = 100_001_usize.div_ceil(memory::PAGE_SIZE);
+    assert_eq!(fork.memory.shared_pages(), pages - 1);
+}
+
+#[test]
+fn budgets_are_enforced() {
+    let mut machine = Machine::from_mem_spec("1101,1,1,5,99,0").unwrap();
+    assert_eq!(machine.run_with_limit(1), Ok(Stop::OutOfSteps { pc: 4 }));
+    assert_eq!(machine.run_with_limit(1), Ok(Stop::Halted));
+    assert_eq!(machine.memory[5], 2);
+
+    // Jumps back to itself forever.
+    let mut machine = Machine::from_mem_spec("1105,1,0").unwrap();
+    assert_eq!(machine.run_with_limit(1000), Ok(Stop::OutOfSteps { pc: 0 }));
+    assert_eq!(
+        machine.run_for(Duration::from_millis(10)),
+        Ok(Stop::TimedOut { pc: 0 })
+    );
+}
+
+#[test]
+fn memory_is_sparse_and_limited() {
+    let mut machine = Machine::from_mem_spec("1101,1,2,1000000000,99").unwrap();
+    machine.run().unwrap();
+    assert_eq!(machine.memory[1_000_000_000], 3);
+    assert_eq!(machine.memory.allocated_pages(), 2);
+
+    let mut machine = Machine::from_mem_spec("1101,1,2,1000000000,99").unwrap();
+    machine.memory_limit = 1000;
+    assert_eq!(
+        machine.run(),
+        Err(MachineError::MemoryLimit {
+            pc: 0,
+            addr: 1_000_000_000,
+            limit: 1000
+        })
+    );
+}
+
+#[test]
+fn wide_words() {
+    use num::bigint::BigInt;
+
+    // Squares its input.
+    let program = "3,13,1002,13,1,14,2,13,14,13,4,13,99,0,0";
+    let square = |input: &str| {
+        let mut machine = Machine::<BigInt>::parse(program).unwrap();
+        machine.input.push_back(input.parse().unwrap());
+        machine.run().unwrap();
+        machine.output[0].to_string()
+    };
+    assert_eq!(
+        square("100000000000000000000"),
+        "10000000000000000000000000000000000000000"
+    );
+
+    let mut machine = Machine::<i128>::parse(program).unwrap();
+    machine.input.push_back(10_000_000_000);
+    machine.run().unwrap();
+    assert_eq!(machine.output, vec![100_000_000_000_000_000_000]);
+
+    let mut machine = Machine::<i64>::parse(program).unwrap();
+    machine.arithmetic = Arithmetic::Checked;
+    machine.input.push_back(10_000_000_000);
+    assert_eq!(machine.run(), Err(MachineError::Overflow { pc: 6 }));
+    // Wrapping is the same in debug and release builds.
+    let mut machine = Machine::<i64>::parse(program).unwrap();
+    machine.input.push_back(10_000_000_000);
+    machine.run().unwrap();
+    assert_eq!(
+        machine.output,
+        vec![10_000_000_000i64.wrapping_mul(10_000_000_000)]
+    );
+}
+
+#[test]
+#[ignore]
+fn bench_fork() {
+    let mut machine = Machine::from_mem_spec("3,100000,99").unwrap();
+    machine.memory.resize(1_000_000);
+    crate::utils::bench("1000 forks of a million words", 10, || {
+        for i in 0..1000 {
+            let mut fork = machine.clone();
+            fork.input.push_back(i);
+            fork.run().unwrap();
+        }
+    });
 }
@@ -0,0 +1,121 @@
+// Some programs talk in text, one character code per value, with lines
+// ending in '\n'. This wraps a machine so we can deal in strings instead.
+use super::{Machine, MachineError, Step};
+use derive_more::Display;
+
+// A line we were asked to send with something in it the program couldn't
+// read. `offset` counts chars, not bytes.
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy)]
+#[display(fmt = "{:?} at offset {} isn't ASCII", c, offset)]
+pub struct NotAscii {
+    pub offset: usize,
+    pub c: char,
+}
+
+impl std::error::Error for NotAscii {}
+
+// Everything a program printed in one go.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Transcript {
+    pub text: String,
+    // Outputs that aren't ASCII. Puzzles use these for the actual answer.
+    pub values: Vec<isize>,
+    // Otherwise the program is waiting for another line.
+    pub halted: bool,
+}
+
+impl Transcript {
+    fn decode(output: &[isize]) -> Self {
+        let mut transcript = Transcript::default();
+        for &value in output {
+            if (0..=127).contains(&value) {
+                transcript.text.push(value as u8 as char);
+            } else {
+                transcript.values.push(value);
+            }
+        }
+        transcript
+    }
+}
+
+pub struct Ascii {
+    pub machine: Machine,
+}
+
+impl Ascii {
+    pub fn new(machine: Machine) -> Self {
+        Ascii { machine }
+    }
+
+    // Queues up a line of input, adding the newline for you. Nothing gets
+    // queued if any of it isn't ASCII.
+    pub fn send_line(&mut self, line: &str) -> Result<(), NotAscii> {
+        if let Some((offset, c)) = line.chars().enumerate().find(|(_, c)| !c.is_ascii()) {
+            return Err(NotAscii { offset, c });
+        }
+        let codes = line.chars().chain(Some('\n')).map(|c| c as isize);
+        self.machine.input.extend(codes);
+        Ok(())
+    }
+
+    // Runs until the program halts or wants more input than we've given it,
+    // and collects what it printed along the way.
+    pub fn run(&mut self) -> Result<Transcript, MachineError> {
+        let halted = loop {
+            match self.machine.step()? {
+                Step::Continue | Step::Output(_) => continue,
+                Step::Input => break false,
+                Step::Halt => break true,
+            }
+        };
+        let mut transcript = Transcript::decode(&self.machine.output);
+        transcript.halted = halted;
+        self.machine.output.clear();
+        Ok(transcript)
+    }
+}
+
+#[test]
+fn ascii_echo() {
+    let program = super::asm::assemble(
+        "
+            out #63
+            out #10
+        loop:
+            in [c]
+            out [c]
+            eq [c], #10 -> [t]
+            jf [t], #loop
+            out #1000
+            hlt
+        c: .data 0
+        t: .data 0
+        ",
+    )
+    .unwrap();
+    let mut ascii = Ascii::new(Machine::from_mem_spec(&program).unwrap());
+    assert_eq!(
+        ascii.run(),
+        Ok(Transcript {
+            text: "?\n".to_string(),
+            values: vec![],
+            halted: false,
+        })
+    );
+    assert_eq!(
+        ascii.send_line("WALK→"),
+        Err(NotAscii {
+            offset: 4, c: '→'
+        })
+    );
+    assert!(ascii.machine.input.is_empty());
+    ascii.send_line("WALK").unwrap();
+    assert_eq!(
+        ascii.run(),
+        Ok(Transcript {
+            text: "WALK\n".to_string(),
+            values: vec![1000],
+            halted: true,
+        })
+    );
+}
@@ -0,0 +1,311 @@
+// A small assembly language for writing Intcode by hand, e.g.
+//
+//         in [n]
+//     loop:
+//         out [n]
+//         add [n], #-1 -> [n]
+//         jt [n], #loop
+//         hlt
+//     n:  .data 0
+//
+// Operands are `#imm`, `[pos]` or `[rb+n]` (`[r+n]` also works, so the
+// disassembler's output can be fed back in). Labels can be used anywhere
+// a number can, except for relative offsets. The operand being written can
+// either go last like in the raw encoding, or after a `->`.
+use super::{ArgMode, Opcode};
+use derive_more::Display;
+use std::collections::HashMap;
+
+#[derive(Debug, Display, PartialEq, Eq, Clone)]
+pub enum AsmError {
+    #[display(fmt = "line {}: unknown mnemonic {:?}", line, mnemonic)]
+    UnknownMnemonic { line: usize, mnemonic: String },
+    #[display(fmt = "line {}: expected {} operands, found {}", line, expected, found)]
+    OperandCount {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[display(fmt = "line {}: could not parse operand {:?}", line, operand)]
+    BadOperand { line: usize, operand: String },
+    #[display(fmt = "line {}: write to immediate operand", line)]
+    ImmediateWrite { line: usize },
+    #[display(fmt = "line {}: unknown label {:?}", line, label)]
+    UnknownLabel { line: usize, label: String },
+    #[display(fmt = "line {}: label {:?} defined twice", line, label)]
+    DuplicateLabel { line: usize, label: String },
+}
+
+impl std::error::Error for AsmError {}
+
+// Numbers can be given literally or by label, which we can only resolve
+// once we've seen the whole program.
+#[derive(Debug, Clone)]
+enum Value {
+    Number(isize),
+    Label(String),
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    Instruction {
+        opcode: Opcode,
+        operands: Vec<(ArgMode, Value)>,
+    },
+    Data(Vec<Value>),
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(line: usize, s: &str) -> Result<Value, AsmError> {
+    let s = s.trim();
+    if let Ok(n) = s.parse() {
+        Ok(Value::Number(n))
+    } else if is_label(s) {
+        Ok(Value::Label(s.to_string()))
+    } else {
+        Err(AsmError::BadOperand {
+            line,
+            operand: s.to_string(),
+        })
+    }
+}
+
+fn parse_operand(line: usize, s: &str) -> Result<(ArgMode, Value), AsmError> {
+    let s = s.trim();
+    let bad = || AsmError::BadOperand {
+        line,
+        operand: s.to_string(),
+    };
+    if let Some(value) = s.strip_prefix('#') {
+        return Ok((ArgMode::Immediate, parse_value(line, value)?));
+    }
+    let inner = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(bad)?
+        .trim();
+    let offset = inner
+        .strip_prefix("rb")
+        .or_else(|| inner.strip_prefix('r'))
+        .filter(|offset| offset.starts_with('+') || offset.starts_with('-'));
+    match offset {
+        Some(offset) => {
+            let offset = offset.trim_start_matches('+').parse().map_err(|_| bad())?;
+            Ok((ArgMode::Relative, Value::Number(offset)))
+        }
+        None => Ok((ArgMode::Position, parse_value(line, inner)?)),
+    }
+}
+
+fn find_opcode(mnemonic: &str) -> Option<Opcode> {
+    (1..=9)
+        .chain(Some(99))
+        .map(Opcode)
+        .find(|opcode| opcode.info().unwrap().0.eq_ignore_ascii_case(mnemonic))
+}
+
+fn parse_item(line: usize, s: &str) -> Result<Item, AsmError> {
+    let (head, rest) = match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    };
+    if head == ".data" {
+        let values = rest
+            .split(',')
+            .map(|value| parse_value(line, value))
+            .collect::<Result<_, _>>()?;
+        return Ok(Item::Data(values));
+    }
+
+    let opcode = find_opcode(head).ok_or_else(|| AsmError::UnknownMnemonic {
+        line,
+        mnemonic: head.to_string(),
+    })?;
+    let (_, arity, writes) = opcode.info().unwrap();
+    let mut operands = Vec::new();
+    for part in rest.split("->") {
+        for operand in part.split(',').filter(|o| !o.trim().is_empty()) {
+            operands.push(parse_operand(line, operand)?);
+        }
+    }
+    if operands.len() != arity {
+        return Err(AsmError::OperandCount {
+            line,
+            expected: arity,
+            found: operands.len(),
+        });
+    }
+    if writes.is_some_and(|arg| operands[arg].0 == ArgMode::Immediate) {
+        return Err(AsmError::ImmediateWrite { line });
+    }
+    Ok(Item::Instruction { opcode, operands })
+}
+
+fn encode(opcode: Opcode, operands: &[(ArgMode, Value)]) -> isize {
+    let mut code = opcode.0 as isize;
+    let mut place = 100;
+    for (mode, _) in operands {
+        code += place
+            * match mode {
+                ArgMode::Position => 0,
+                ArgMode::Immediate => 1,
+                ArgMode::Relative => 2,
+            };
+        place *= 10;
+    }
+    code
+}
+
+// Assembles a program into the comma-separated form that
+// `Machine::from_mem_spec` expects.
+pub fn assemble(src: &str) -> Result<String, AsmError> {
+    // First pass: parse everything and work out where the labels are.
+    let mut labels = HashMap::new();
+    let mut items = Vec::new();
+    let mut addr = 0;
+    for (i, text) in src.lines().enumerate() {
+        let line = i + 1;
+        let mut text = text.split(';').next().unwrap().trim();
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_label(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    label: label.to_string(),
+                });
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let item = parse_item(line, text)?;
+        addr += match &item {
+            Item::Instruction { operands, .. } => 1 + operands.len(),
+            Item::Data(values) => values.len(),
+        };
+        items.push((line, item));
+    }
+
+    // Second pass: fill in the labels.
+    let resolve = |line: usize, value: &Value| match value {
+        Value::Number(n) => Ok(*n),
+        Value::Label(label) => {
+            labels
+                .get(label)
+                .map(|&addr| addr as isize)
+                .ok_or_else(|| AsmError::UnknownLabel {
+                    line,
+                    label: label.clone(),
+                })
+        }
+    };
+    let mut memory = Vec::new();
+    for (line, item) in &items {
+        match item {
+            Item::Instruction { opcode, operands } => {
+                memory.push(encode(*opcode, operands));
+                for (_, value) in operands {
+                    memory.push(resolve(*line, value)?);
+                }
+            }
+            Item::Data(values) => {
+                for value in values {
+                    memory.push(resolve(*line, value)?);
+                }
+            }
+        }
+    }
+    Ok(memory
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+#[test]
+fn assemble_countdown() {
+    let src = "
+        ; Counts down from the input to 1.
+            in [n]
+        loop:
+            out [n]
+            add [n], #-1 -> [n]
+            jt [n], #loop
+            hlt
+        n:  .data 0
+    ";
+    let program = assemble(src).unwrap();
+    assert_eq!(program, "3,12,4,12,1001,12,-1,12,1005,12,2,99,0");
+    let mut machine = super::Machine::from_mem_spec(&program).unwrap();
+    machine.input.push_back(3);
+    machine.run().unwrap();
+    assert_eq!(&machine.output, &[3, 2, 1]);
+}
+
+#[test]
+fn assemble_round_trips_disassembly() {
+    let programs = [super::BIG_TEST, "109,19,204,-34,21101,3,5,7,99"];
+    for program in programs.iter() {
+        let machine = super::Machine::from_mem_spec(program).unwrap();
+        let listing = super::disasm::disassemble(&machine.memory.to_vec()).to_string();
+        // Strip the `0012: ` address prefixes.
+        let src = listing
+            .lines()
+            .map(|line| &line[6..])
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(&assemble(&src).unwrap(), program);
+    }
+}
+
+#[test]
+fn assemble_errors() {
+    assert_eq!(
+        assemble("add #1, #2"),
+        Err(AsmError::OperandCount {
+            line: 1,
+            expected: 3,
+            found: 2
+        })
+    );
+    assert_eq!(
+        assemble("hlt\nmov [1], [2]"),
+        Err(AsmError::UnknownMnemonic {
+            line: 2,
+            mnemonic: "mov".to_string()
+        })
+    );
+    assert_eq!(assemble("in #3"), Err(AsmError::ImmediateWrite { line: 1 }));
+    assert_eq!(
+        assemble("jt #1, #nowhere"),
+        Err(AsmError::UnknownLabel {
+            line: 1,
+            label: "nowhere".to_string()
+        })
+    );
+    assert_eq!(
+        assemble("a: hlt\na: hlt"),
+        Err(AsmError::DuplicateLabel {
+            line: 2,
+            label: "a".to_string()
+        })
+    );
+    assert_eq!(
+        assemble("out [rb+x]"),
+        Err(AsmError::BadOperand {
+            line: 1,
+            operand: "[rb+x]".to_string()
+        })
+    );
+}
@@ -0,0 +1,264 @@
+// Recovers a program's control flow graph, for looking at in Graphviz or
+// poking at with petgraph.
+use super::disasm::{find_code_from, Instruction};
+use super::{ArgMode, Opcode};
+use petgraph::dot::Dot;
+use petgraph::graph::NodeIndex;
+use petgraph::Graph;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// A run of instructions that always execute together, top to bottom.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    // Ends by jumping wherever memory says, which is usually a return.
+    pub indirect: bool,
+}
+
+impl Block {
+    pub fn start(&self) -> usize {
+        self.instructions[0].addr
+    }
+
+    pub fn end(&self) -> usize {
+        self.last().next_addr()
+    }
+
+    fn last(&self) -> &Instruction {
+        self.instructions.last().unwrap()
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in &self.instructions {
+            writeln!(f, "{:04}: {}", instruction.addr, instruction)?;
+        }
+        if self.indirect {
+            writeln!(f, "(indirect)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Edge {
+    Jump,
+    FallThrough,
+    // A jump that pushed its own return address first, see `return_site`.
+    Call,
+    // From a call to wherever the subroutine comes back to.
+    Return,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Edge::Jump => "jump",
+            Edge::FallThrough => "fall through",
+            Edge::Call => "call",
+            Edge::Return => "return",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub struct Cfg {
+    pub graph: Graph<Block, Edge>,
+    // Keyed by start address.
+    blocks: BTreeMap<usize, NodeIndex>,
+}
+
+impl Cfg {
+    // The block starting at `addr`, if there is one.
+    pub fn block_at(&self, addr: usize) -> Option<NodeIndex> {
+        self.blocks.get(&addr).copied()
+    }
+
+    // Every edge as (from, to, kind), by block start address.
+    pub fn edges(&self) -> Vec<(usize, usize, Edge)> {
+        let mut edges: Vec<_> = self
+            .graph
+            .raw_edges()
+            .iter()
+            .map(|edge| {
+                let from = self.graph[edge.source()].start();
+                let to = self.graph[edge.target()].start();
+                (from, to, edge.weight)
+            })
+            .collect();
+        edges.sort_by_key(|&(from, to, _)| (from, to));
+        edges
+    }
+
+    pub fn to_dot(&self) -> String {
+        Dot::new(&self.graph).to_string()
+    }
+}
+
+// What an ADD or MUL with two immediate operands writes relative to the
+// relative base, which is how programs push constants onto their stack.
+fn pushed_constant(instruction: &Instruction) -> Option<isize> {
+    let immediate =
+        instruction.modes[..] == [ArgMode::Immediate, ArgMode::Immediate, ArgMode::Relative];
+    match instruction.opcode {
+        // Anything that overflows is no address worth returning to.
+        Opcode(1) if immediate => instruction.args[0].checked_add(instruction.args[1]),
+        Opcode(2) if immediate => instruction.args[0].checked_mul(instruction.args[1]),
+        _ => None,
+    }
+}
+
+// If `jump` is a subroutine call, where the subroutine will return to.
+// There's no call instruction, so calls are an unconditional jump preceded
+// by pushing the address just past the jump. The return is then an
+// indirect jump through the stack.
+fn return_site(code: &BTreeMap<usize, Instruction>, jump: &Instruction) -> Option<usize> {
+    if jump.falls_through() || jump.jump_target().is_none() {
+        return None;
+    }
+    let site = jump.next_addr();
+    let mut addr = jump.addr;
+    for instruction in code.range(..jump.addr).rev().map(|(_, i)| i) {
+        let ends_block = matches!(instruction.opcode, Opcode(5) | Opcode(6) | Opcode(99));
+        if instruction.next_addr() != addr || ends_block {
+            break;
+        }
+        if pushed_constant(instruction) == Some(site as isize) {
+            return Some(site);
+        }
+        addr = instruction.addr;
+    }
+    None
+}
+
+pub fn build_cfg(memory: &[isize]) -> Cfg {
+    // Return sites are only found once the calls to them are, so keep
+    // going until there aren't any new ones.
+    let mut roots = vec![0];
+    let mut code = find_code_from(memory, &roots);
+    loop {
+        let sites: BTreeSet<usize> = code
+            .values()
+            .filter_map(|instruction| return_site(&code, instruction))
+            .filter(|site| !roots.contains(site))
+            .collect();
+        if sites.is_empty() {
+            break;
+        }
+        roots.extend(sites);
+        code = find_code_from(memory, &roots);
+    }
+
+    let mut leaders: BTreeSet<usize> = roots.iter().copied().collect();
+    for instruction in code.values() {
+        leaders.extend(instruction.jump_target());
+        if let Opcode(5) | Opcode(6) | Opcode(99) = instruction.opcode {
+            leaders.insert(instruction.next_addr());
+        }
+    }
+
+    let mut graph = Graph::new();
+    let mut blocks = BTreeMap::new();
+    let mut current: Vec<Instruction> = Vec::new();
+    for instruction in code.values() {
+        let split = current.last().is_some_and(|last| {
+            leaders.contains(&instruction.addr) || last.next_addr() != instruction.addr
+        });
+        if split {
+            let start = current[0].addr;
+            let block = Block {
+                instructions: std::mem::take(&mut current),
+                indirect: false,
+            };
+            blocks.insert(start, graph.add_node(block));
+        }
+        current.push(instruction.clone());
+    }
+    if !current.is_empty() {
+        let start = current[0].addr;
+        let block = Block {
+            instructions: current,
+            indirect: false,
+        };
+        blocks.insert(start, graph.add_node(block));
+    }
+
+    for &node in blocks.values() {
+        let last = graph[node].last().clone();
+        let site = return_site(&code, &last);
+        if let Opcode(5) | Opcode(6) = last.opcode {
+            graph[node].indirect = last.jump_target().is_none();
+        }
+        if let Some(&target) = last.jump_target().and_then(|addr| blocks.get(&addr)) {
+            let edge = if site.is_some() {
+                Edge::Call
+            } else {
+                Edge::Jump
+            };
+            graph.add_edge(node, target, edge);
+        }
+        if let Some(&target) = site.and_then(|addr| blocks.get(&addr)) {
+            graph.add_edge(node, target, Edge::Return);
+        }
+        if last.falls_through() {
+            if let Some(&target) = blocks.get(&last.next_addr()) {
+                graph.add_edge(node, target, Edge::FallThrough);
+            }
+        }
+    }
+    Cfg { graph, blocks }
+}
+
+#[cfg(test)]
+static SUBROUTINE: &str = "
+        add #back, #0 -> [rb+0]
+        jt #1, #double
+back:   out [x]
+        hlt
+double: mul [x], #2 -> [x]
+        jt #1, [rb+0]
+x:      .data 21
+";
+
+#[test]
+fn cfg_follows_calls() {
+    let program = super::asm::assemble(SUBROUTINE).unwrap();
+    let mut machine = super::Machine::from_mem_spec(&program).unwrap();
+    let cfg = build_cfg(&machine.memory.to_vec());
+    assert_eq!(cfg.edges(), vec![(0, 7, Edge::Return), (0, 10, Edge::Call)]);
+    let subroutine = &cfg.graph[cfg.block_at(10).unwrap()];
+    assert!(subroutine.indirect);
+    assert_eq!(subroutine.end(), 17);
+    assert_eq!(cfg.block_at(4), None);
+
+    // Make sure the calling convention really is what it looks like.
+    machine.run().unwrap();
+    assert_eq!(machine.output, vec![42]);
+}
+
+#[test]
+fn cfg_to_dot() {
+    let program = super::asm::assemble(SUBROUTINE).unwrap();
+    let memory = super::Machine::from_mem_spec(&program)
+        .unwrap()
+        .memory
+        .to_vec();
+    let expected = r#"digraph {
+    0 [label="0000: ADD #7, #0 -> [r+0]\l0004: JT #1, #10\l"]
+    1 [label="0007: OUT [17]\l0009: HLT\l"]
+    2 [label="0010: MUL [17], #2 -> [17]\l0014: JT #1, [r+0]\l(indirect)\l"]
+    0 -> 2 [label="call"]
+    0 -> 1 [label="return"]
+}
+"#;
+    assert_eq!(build_cfg(&memory).to_dot(), expected);
+}
+
+#[test]
+fn cfg_ignores_overflowing_pushes() {
+    // Pushes 2^62 * 4, then jumps.
+    let cfg = build_cfg(&[21102, 1 << 62, 4, 0, 1105, 1, 8, 99, 99]);
+    assert_eq!(cfg.edges(), vec![(0, 8, Edge::Jump)]);
+}
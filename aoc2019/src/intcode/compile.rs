@@ -0,0 +1,401 @@
+// Compiles a program's basic blocks into closures with all the decoding and
+// operand lookup done up front, for when the same program is run over and
+// over again.
+//
+// Every block remembers the words it was compiled from, and checks them on
+// the way in. Any instruction that has changed since, whether the program
+// modified itself or somebody patched its memory from outside, goes through
+// the interpreter instead.
+use super::cfg::build_cfg;
+use super::disasm::Instruction;
+use super::io::{IoDevice, Queues};
+use super::{ArgMode, Arithmetic, Machine, MachineError, Opcode, Step};
+
+#[derive(Debug, Copy, Clone)]
+enum Operand {
+    Immediate(isize),
+    Position(usize),
+    Relative(isize),
+}
+
+impl Operand {
+    // `None` for negative positions, which have to fault at run time.
+    fn new(mode: ArgMode, arg: isize) -> Option<Self> {
+        Some(match mode {
+            ArgMode::Immediate => Operand::Immediate(arg),
+            ArgMode::Position if arg < 0 => return None,
+            ArgMode::Position => Operand::Position(arg as usize),
+            ArgMode::Relative => Operand::Relative(arg),
+        })
+    }
+
+    fn address(self, machine: &mut Machine) -> Result<usize, MachineError> {
+        let addr = match self {
+            Operand::Position(addr) => addr,
+            Operand::Relative(offset) => {
+                machine.address(machine.relative_base.saturating_add(offset))?
+            }
+            Operand::Immediate(_) => return Err(MachineError::ImmediateWrite { pc: machine.pc }),
+        };
+        machine.grow_mem(addr)?;
+        Ok(addr)
+    }
+
+    fn load(self, machine: &mut Machine) -> Result<isize, MachineError> {
+        match self {
+            Operand::Immediate(value) => Ok(value),
+            operand => {
+                let addr = operand.address(machine)?;
+                Ok(machine.memory[addr])
+            }
+        }
+    }
+}
+
+// What to do after an instruction.
+enum Flow {
+    Next,
+    Stored(usize),
+    Jump(usize),
+    Blocked,
+    Halt,
+}
+
+type Run = Box<dyn Fn(&mut Machine, &mut dyn IoDevice) -> Result<Flow, MachineError> + Send + Sync>;
+
+struct Op {
+    addr: usize,
+    next: usize,
+    run: Run,
+}
+
+struct Block {
+    start: usize,
+    end: usize,
+    // What memory looked like when this was compiled.
+    words: Vec<isize>,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    fn intact(&self, machine: &Machine, start: usize, end: usize) -> bool {
+        let words = &self.words[start - self.start..end - self.start];
+        (start..end)
+            .zip(words)
+            .all(|(addr, word)| machine.memory.get(addr) == Some(word))
+    }
+}
+
+fn arithmetic(
+    machine: &Machine,
+    checked: Option<isize>,
+    wrapped: isize,
+) -> Result<isize, MachineError> {
+    match machine.arithmetic {
+        Arithmetic::Wrapping => Ok(wrapped),
+        Arithmetic::Checked => checked.ok_or(MachineError::Overflow { pc: machine.pc }),
+    }
+}
+
+fn store(machine: &mut Machine, target: Operand, value: isize) -> Result<Flow, MachineError> {
+    let addr = target.address(machine)?;
+    machine.memory[addr] = value;
+    Ok(Flow::Stored(addr))
+}
+
+// `None` if the instruction has an operand that will always fault, which
+// is left for the interpreter to report.
+fn compile_op(instruction: &Instruction) -> Option<Op> {
+    let addr = instruction.addr;
+    let next = instruction.next_addr();
+    let operands: Option<Vec<Operand>> = (instruction.modes.iter())
+        .zip(&instruction.args)
+        .map(|(&mode, &arg)| Operand::new(mode, arg))
+        .collect();
+    let operands = operands?;
+    let arg = |i: usize| operands[i];
+    let run: Run = match instruction.opcode {
+        Opcode(1) => {
+            let (a, b, c) = (arg(0), arg(1), arg(2));
+            Box::new(move |machine, _| {
+                machine.pc = addr;
+                let (a, b) = (a.load(machine)?, b.load(machine)?);
+                let sum = arithmetic(machine, a.checked_add(b), a.wrapping_add(b))?;
+                store(machine, c, sum)
+            })
+        }
+        Opcode(2) => {
+            let (a, b, c) = (arg(0), arg(1), arg(2));
+            Box::new(move |machine, _| {
+                machine.pc = addr;
+                let (a, b) = (a.load(machine)?, b.load(machine)?);
+                let product = arithmetic(machine, a.checked_mul(b), a.wrapping_mul(b))?;
+                store(machine, c, product)
+            })
+        }
+        Opcode(3) => {
+            let a = arg(0);
+            Box::new(move |machine, io| {
+                machine.pc = addr;
+                match io.read() {
+                    Ok(value) => store(machine, a, value),
+                    Err(_) => Ok(Flow::Blocked),
+                }
+            })
+        }
+        Opcode(4) => {
+            let a = arg(0);
+            Box::new(move |machine, io| {
+                machine.pc = addr;
+                io.write(a.load(machine)?);
+                Ok(Flow::Next)
+            })
+        }
+        Opcode(5) | Opcode(6) => {
+            let (a, b) = (arg(0), arg(1));
+            let when = instruction.opcode == Opcode(5);
+            Box::new(move |machine, _| {
+                machine.pc = addr;
+                if (a.load(machine)? != 0) == when {
+                    let target = b.load(machine)?;
+                    Ok(Flow::Jump(machine.address(target)?))
+                } else {
+                    Ok(Flow::Jump(next))
+                }
+            })
+        }
+        Opcode(7) | Opcode(8) => {
+            let (a, b, c) = (arg(0), arg(1), arg(2));
+            let less = instruction.opcode == Opcode(7);
+            Box::new(move |machine, _| {
+                machine.pc = addr;
+                let (a, b) = (a.load(machine)?, b.load(machine)?);
+                let result = if less { a < b } else { a == b };
+                store(machine, c, result as isize)
+            })
+        }
+        Opcode(9) => {
+            let a = arg(0);
+            Box::new(move |machine, _| {
+                machine.pc = addr;
+                let offset = a.load(machine)?;
+                let base = machine.relative_base;
+                machine.relative_base =
+                    arithmetic(machine, base.checked_add(offset), base.wrapping_add(offset))?;
+                Ok(Flow::Next)
+            })
+        }
+        Opcode(99) => Box::new(move |machine, _| {
+            machine.pc = addr;
+            Ok(Flow::Halt)
+        }),
+        _ => return None,
+    };
+    Some(Op { addr, next, run })
+}
+
+// A compiled program, which can be shared between any number of machines
+// loaded with (more or less) the same program.
+pub struct Compiled {
+    // Indexed by start address.
+    blocks: Vec<Option<Block>>,
+    // Which addresses the compiled code was compiled from.
+    code: Vec<bool>,
+}
+
+impl Compiled {
+    pub fn new(memory: &[isize]) -> Self {
+        let cfg = build_cfg(memory);
+        let mut blocks: Vec<Option<Block>> = (0..memory.len()).map(|_| None).collect();
+        let mut code = vec![false; memory.len()];
+        for block in cfg.graph.node_indices().map(|i| &cfg.graph[i]) {
+            let (start, end) = (block.start(), block.end());
+            let ops: Option<Vec<Op>> = (block.instructions.iter()).map(compile_op).collect();
+            // Anything with a doomed operand is left to the interpreter.
+            if let Some(ops) = ops {
+                for c in &mut code[start..end] {
+                    *c = true;
+                }
+                blocks[start] = Some(Block {
+                    start,
+                    end,
+                    words: memory[start..end].to_vec(),
+                    ops,
+                });
+            }
+        }
+        Compiled { blocks, code }
+    }
+
+    // Like `Machine::run`.
+    pub fn run(&self, machine: &mut Machine) -> Result<(), MachineError> {
+        let mut queues = Queues {
+            input: std::mem::take(&mut machine.input),
+            output: std::mem::take(&mut machine.output),
+        };
+        let step = self.run_with(machine, &mut queues);
+        machine.input = queues.input;
+        machine.output = queues.output;
+        match step? {
+            Step::Input => Err(MachineError::MissingInput { pc: machine.pc }),
+            _ => Ok(()),
+        }
+    }
+
+    // Like `Machine::run_with`. Machines that are being traced, profiled,
+    // watched or checked for self-modification are interpreted, since
+    // compiled code doesn't report to any of those. So are ones with a
+    // different instruction set, which the compiled code doesn't follow.
+    pub fn run_with(
+        &self,
+        machine: &mut Machine,
+        io: &mut dyn IoDevice,
+    ) -> Result<Step, MachineError> {
+        let instrumented = machine.trace.is_some()
+            || machine.profile.is_some()
+            || machine.watchpoints.is_some()
+            || machine.self_modification.is_some()
+            || !machine.instructions().is_full();
+        if instrumented {
+            return machine.run_with(io);
+        }
+        // Checking the whole program once up front means blocks don't need
+        // checking on the way in, until something writes over one of them.
+        let mut trusted = (self.blocks.iter().flatten())
+            .all(|block| block.intact(machine, block.start, block.end));
+        loop {
+            let step = match self.blocks.get(machine.pc) {
+                Some(Some(block)) => self.run_block(block, machine, io, &mut trusted)?,
+                // Somewhere static analysis didn't find, or the middle of a
+                // block. The interpreter might write anywhere.
+                _ => {
+                    trusted = false;
+                    match machine.step_with(io)? {
+                        Step::Output(_) | Step::Continue => None,
+                        step => Some(step),
+                    }
+                }
+            };
+            if let Some(step) = step {
+                return Ok(step);
+            }
+        }
+    }
+
+    // Returns the step to stop at if the machine halts or blocks, otherwise
+    // leaves `pc` wherever execution goes next.
+    fn run_block(
+        &self,
+        block: &Block,
+        machine: &mut Machine,
+        io: &mut dyn IoDevice,
+        trusted: &mut bool,
+    ) -> Result<Option<Step>, MachineError> {
+        let intact = *trusted || block.intact(machine, block.start, block.end);
+        for op in &block.ops {
+            if !intact && !block.intact(machine, op.addr, op.next) {
+                machine.pc = op.addr;
+                match machine.step_with(io)? {
+                    Step::Output(_) | Step::Continue if machine.pc == op.next => continue,
+                    Step::Output(_) | Step::Continue => return Ok(None),
+                    step => return Ok(Some(step)),
+                }
+            }
+            match (op.run)(machine, io)? {
+                Flow::Next => (),
+                // Stop there, so the rest of the block gets checked again.
+                Flow::Stored(addr) if self.code.get(addr) == Some(&true) => {
+                    *trusted = false;
+                    machine.pc = op.next;
+                    return Ok(None);
+                }
+                Flow::Stored(_) => (),
+                Flow::Jump(target) => {
+                    machine.pc = target;
+                    return Ok(None);
+                }
+                Flow::Blocked => return Ok(Some(Step::Input)),
+                Flow::Halt => return Ok(Some(Step::Halt)),
+            }
+        }
+        machine.pc = block.end;
+        Ok(None)
+    }
+}
+
+#[test]
+fn compiled_matches_interpreter() {
+    let check = |program: &str, input: Vec<isize>| {
+        let mut interpreted = Machine::from_mem_spec(program).unwrap();
+        interpreted.input.extend(input.iter().copied());
+        let expected = interpreted.run();
+        let mut compiled = Machine::from_mem_spec(program).unwrap();
+        compiled.input.extend(input);
+        let result = Compiled::new(&compiled.memory.to_vec()).run(&mut compiled);
+        assert_eq!(result, expected);
+        assert_eq!(compiled.output, interpreted.output);
+        assert_eq!(compiled.memory, interpreted.memory);
+        assert_eq!(compiled.pc, interpreted.pc);
+    };
+    for input in 6..11 {
+        check(super::BIG_TEST, vec![input]);
+    }
+    // Day 9's quine, which uses the relative base and grows memory.
+    check(
+        "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99",
+        vec![],
+    );
+    // Faults still get the right pc.
+    check("1101,1,1,5,1,-3,0,0,99", vec![]);
+    check("3,0,99", vec![]);
+    // Pushes a constant too big to be a return address.
+    check("21102,4611686018427387904,4,0,1105,1,8,99,99", vec![]);
+}
+
+#[test]
+fn compiled_code_can_be_modified() {
+    use super::asm::assemble;
+
+    // Rewrites the ADD in the middle of its own block into a MUL.
+    let program = assemble(
+        "
+            add #1102, #0 -> [op]
+        op: add #6, #7 -> [x]
+            out [x]
+            hlt
+        x:  .data 0
+        ",
+    )
+    .unwrap();
+    let machine = Machine::from_mem_spec(&program).unwrap();
+    let compiled = Compiled::new(&machine.memory.to_vec());
+    let mut fresh = machine.clone();
+    compiled.run(&mut fresh).unwrap();
+    assert_eq!(fresh.output, vec![42]);
+
+    // Patching from outside, the way day 2 sets its noun and verb.
+    let mut patched = machine.clone();
+    patched.memory[5] = 5;
+    compiled.run(&mut patched).unwrap();
+    assert_eq!(patched.output, vec![35]);
+}
+
+#[test]
+#[ignore]
+fn bench_compiled() {
+    let program = super::asm::assemble(
+        "
+        loop:
+            add [n], #-1 -> [n]
+            jt [n], #loop
+            hlt
+        n: .data 1000000
+        ",
+    )
+    .unwrap();
+    let machine = Machine::from_mem_spec(&program).unwrap();
+    let compiled = Compiled::new(&machine.memory.to_vec());
+    crate::utils::bench("2 million compiled instructions", 10, || {
+        compiled.run(&mut machine.clone()).unwrap();
+    });
+}
@@ -0,0 +1,215 @@
+// Which parts of a program a set of runs actually exercised.
+use super::disasm::{find_code, Instruction};
+use super::trace::{Entry, Trace};
+use super::{ArgMode, Machine, MachineError, Opcode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// Built up from the traces of any number of runs of the same program.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    memory: Vec<isize>,
+    // What static analysis thinks is code, see `disasm::find_code`.
+    code: BTreeMap<usize, Instruction>,
+    hits: BTreeMap<usize, u64>,
+    // How often each jump was taken and not taken, or each comparison was
+    // true and false.
+    branches: BTreeMap<usize, (u64, u64)>,
+}
+
+// Whether an instruction could go either way. Jumps on a constant are
+// how Intcode spells an unconditional jump, so they don't count.
+fn is_branch(instruction: &Instruction) -> bool {
+    let immediate = |arg: usize| instruction.modes[arg] == ArgMode::Immediate;
+    match instruction.opcode {
+        Opcode(5) | Opcode(6) => !immediate(0),
+        Opcode(7) | Opcode(8) => !(immediate(0) && immediate(1)),
+        _ => false,
+    }
+}
+
+impl Coverage {
+    pub fn new(memory: &[isize]) -> Self {
+        Coverage {
+            memory: memory.to_vec(),
+            code: find_code(memory),
+            hits: BTreeMap::new(),
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, trace: &Trace) {
+        self.record_entries(&trace.entries);
+    }
+
+    fn record_entries(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            *self.hits.entry(entry.pc).or_insert(0) += 1;
+            let outcome = match entry.opcode {
+                Opcode(5) | Opcode(6) => entry.next_pc != entry.pc + 3,
+                Opcode(7) | Opcode(8) => entry.write.is_some_and(|write| write.new != 0),
+                _ => continue,
+            };
+            let (yes, no) = self.branches.entry(entry.pc).or_insert((0, 0));
+            if outcome {
+                *yes += 1;
+            } else {
+                *no += 1;
+            }
+        }
+    }
+
+    // Runs `machine` with tracing on and records it. It should have been
+    // loaded with the same program this was created with. A trace that was
+    // already on carries on as usual, with only this run recorded from it.
+    pub fn run(&mut self, machine: &mut Machine) -> Result<(), MachineError> {
+        let traced = machine.trace.is_some();
+        let trace = machine.trace.get_or_insert_with(Trace::default);
+        let start = trace.entries.len();
+        let result = machine.run();
+        if let Some(trace) = &machine.trace {
+            self.record_entries(&trace.entries[start..]);
+        }
+        if !traced {
+            machine.trace = None;
+        }
+        result
+    }
+
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(&addr).copied().unwrap_or(0)
+    }
+
+    // (taken, not taken) for jumps, (true, false) for comparisons.
+    pub fn branch(&self, addr: usize) -> (u64, u64) {
+        self.branches.get(&addr).copied().unwrap_or((0, 0))
+    }
+
+    // Everything that's code or got executed, whichever way it was found.
+    fn instructions(&self) -> BTreeMap<usize, Option<Instruction>> {
+        let mut instructions: BTreeMap<_, _> = (self.code.iter())
+            .map(|(&addr, instruction)| (addr, Some(instruction.clone())))
+            .collect();
+        for &addr in self.hits.keys() {
+            instructions
+                .entry(addr)
+                .or_insert_with(|| Instruction::decode(&self.memory, addr));
+        }
+        instructions
+    }
+
+    pub fn never_executed(&self) -> Vec<usize> {
+        (self.code.keys().copied())
+            .filter(|&addr| self.hits(addr) == 0)
+            .collect()
+    }
+
+    // Branches that have only ever gone one way, or not at all.
+    pub fn partial_branches(&self) -> Vec<usize> {
+        let branches: BTreeSet<usize> = (self.instructions().into_iter())
+            .filter(|(_, instruction)| instruction.as_ref().is_some_and(is_branch))
+            .map(|(addr, _)| addr)
+            .collect();
+        (branches.into_iter())
+            .filter(|&addr| {
+                let (yes, no) = self.branch(addr);
+                yes == 0 || no == 0
+            })
+            .collect()
+    }
+
+    // The program listing with execution counts down the side, `-----` for
+    // code that never ran, and which ways each branch went.
+    pub fn report(&self) -> String {
+        let instructions = self.instructions();
+        let branches = (instructions.values().flatten())
+            .filter(|instruction| is_branch(instruction))
+            .count();
+        let mut report = String::new();
+        writeln!(
+            report,
+            "{} of {} instructions executed, {} of {} branches went both ways",
+            instructions.len() - self.never_executed().len(),
+            instructions.len(),
+            branches - self.partial_branches().len(),
+            branches
+        )
+        .unwrap();
+        for (addr, instruction) in instructions {
+            let hits = match self.hits(addr) {
+                0 => "-----".to_string(),
+                hits => hits.to_string(),
+            };
+            let opcode = instruction.as_ref().map(|i| i.opcode);
+            let text = instruction.map_or_else(|| "???".to_string(), |i| i.to_string());
+            write!(report, "{:>7}  {:04}: {}", hits, addr, text).unwrap();
+            let (yes, no) = self.branch(addr);
+            match opcode {
+                Some(Opcode(5)) | Some(Opcode(6)) => {
+                    write!(report, "  (taken {}, not taken {})", yes, no).unwrap()
+                }
+                Some(Opcode(7)) | Some(Opcode(8)) => {
+                    write!(report, "  (true {}, false {})", yes, no).unwrap()
+                }
+                _ => (),
+            }
+            writeln!(report).unwrap();
+        }
+        report
+    }
+}
+
+#[test]
+fn coverage_accumulates() {
+    // Outputs 1 if its input is less than 8.
+    let program = "3,13,1007,13,8,12,1006,12,11,104,1,99,0,0";
+    let run = |coverage: &mut Coverage, input| {
+        let mut machine = Machine::from_mem_spec(program).unwrap();
+        machine.input.push_back(input);
+        coverage.run(&mut machine).unwrap();
+    };
+    let memory = Machine::from_mem_spec(program).unwrap().memory.to_vec();
+    let mut coverage = Coverage::new(&memory);
+    run(&mut coverage, 10);
+    assert_eq!(coverage.never_executed(), vec![9]);
+    assert_eq!(coverage.partial_branches(), vec![2, 6]);
+    run(&mut coverage, 3);
+    assert!(coverage.never_executed().is_empty());
+    assert!(coverage.partial_branches().is_empty());
+    assert_eq!(coverage.branch(6), (1, 1));
+    let expected = "\
+5 of 5 instructions executed, 2 of 2 branches went both ways
+      2  0000: IN -> [13]
+      2  0002: LT [13], #8 -> [12]  (true 1, false 1)
+      2  0006: JF [12], #11  (taken 1, not taken 1)
+      1  0009: OUT #1
+      2  0011: HLT
+";
+    assert_eq!(coverage.report(), expected);
+}
+
+#[test]
+fn coverage_keeps_existing_traces() {
+    let program = "104,1,99";
+    let memory = Machine::from_mem_spec(program).unwrap().memory.to_vec();
+    let mut coverage = Coverage::new(&memory);
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    coverage.run(&mut machine).unwrap();
+    assert!(machine.trace.is_none());
+
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    machine.trace = Some(Trace::default());
+    machine.step().unwrap();
+    coverage.run(&mut machine).unwrap();
+    assert_eq!(machine.trace.unwrap().entries.len(), 2);
+    // The step taken before coverage started isn't counted.
+    assert_eq!(coverage.hits(0), 1);
+    assert_eq!(coverage.hits(2), 2);
+
+    // Recording a whole trace does count it.
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    machine.trace = Some(Trace::default());
+    machine.run().unwrap();
+    coverage.record(machine.trace.as_ref().unwrap());
+    assert_eq!(coverage.hits(0), 2);
+}
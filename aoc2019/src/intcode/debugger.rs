@@ -0,0 +1,394 @@
+use super::disasm::Instruction;
+use super::watch::Watch;
+use super::{Machine, Step};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+static HELP: &str = "\
+s [n]          step n instructions (default 1)
+c              continue until a breakpoint, halt, or the machine wants input
+b <addr>       set a breakpoint
+d <addr>       delete a breakpoint
+w <addr> [n]   stop after any write to n cells (default 1) from addr
+dw <addr>      delete the watchpoints covering addr
+l [addr] [n]   disassemble n instructions (default 10) from addr (default pc)
+x <addr> [n]   show n memory cells (default 1) from addr
+set <addr> <v> write v to memory
+pc [v]         show or set the program counter
+rb [v]         show or set the relative base
+in [v...]      show the input queue, or push values onto it
+in clear       empty the input queue
+out            show everything output so far
+q              quit
+";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Command {
+    Step(usize),
+    Continue,
+    Break(usize),
+    Delete(usize),
+    WatchWrites(usize, usize),
+    DeleteWatch(usize),
+    List(Option<usize>, usize),
+    Examine(usize, usize),
+    Set(usize, isize),
+    Pc(Option<usize>),
+    RelativeBase(Option<isize>),
+    Input(Vec<isize>),
+    ClearInput,
+    Output,
+    Help,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    use Command::*;
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+    fn num<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+        s.parse().map_err(|_| format!("not a number: {}", s))
+    }
+    fn opt<T: std::str::FromStr>(s: Option<&&str>) -> Result<Option<T>, String> {
+        s.map(|s| num(s)).transpose()
+    }
+    Ok(match (name, args.as_slice()) {
+        ("s", [..]) if args.len() <= 1 => Step(opt(args.first())?.unwrap_or(1)),
+        ("c", []) => Continue,
+        ("b", [addr]) => Break(num(addr)?),
+        ("d", [addr]) => Delete(num(addr)?),
+        ("w", [addr]) => WatchWrites(num(addr)?, 1),
+        ("w", [addr, n]) => WatchWrites(num(addr)?, num(n)?),
+        ("dw", [addr]) => DeleteWatch(num(addr)?),
+        ("l", [..]) if args.len() <= 2 => List(opt(args.first())?, opt(args.get(1))?.unwrap_or(10)),
+        ("x", [addr]) => Examine(num(addr)?, 1),
+        ("x", [addr, n]) => Examine(num(addr)?, num(n)?),
+        ("set", [addr, value]) => Set(num(addr)?, num(value)?),
+        ("pc", [..]) if args.len() <= 1 => Pc(opt(args.first())?),
+        ("rb", [..]) if args.len() <= 1 => RelativeBase(opt(args.first())?),
+        ("in", ["clear"]) => ClearInput,
+        ("in", values) => Input(values.iter().map(|v| num(v)).collect::<Result<_, _>>()?),
+        ("out", []) => Output,
+        ("h", []) | ("help", []) => Help,
+        ("q", []) => Quit,
+        _ => return Err(format!("unknown command: {} (try `help`)", line.trim())),
+    })
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    pub breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    fn describe(&self, addr: usize) -> String {
+        match Instruction::decode_at(&self.machine.memory, addr, self.machine.instructions()) {
+            Some(instruction) => format!("{:04}: {}", addr, instruction),
+            None => format!(
+                "{:04}: .data {}",
+                addr,
+                self.machine.memory.get(addr).copied().unwrap_or(0)
+            ),
+        }
+    }
+
+    // The `n` cells from `addr`, as long as the machine could use them all.
+    fn cells(&self, addr: usize, n: usize) -> Result<Range<usize>, String> {
+        let limit = self.machine.memory_limit;
+        match addr.checked_add(n) {
+            Some(end) if end <= limit => Ok(addr..end),
+            _ => Err(format!(
+                "address {} is past the memory limit of {}",
+                addr.saturating_add(n.max(1) - 1),
+                limit
+            )),
+        }
+    }
+
+    // Steps once, reporting anything interesting. Returns whether it's
+    // worth carrying on.
+    fn step(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        let carry_on = self.step_machine(out)?;
+        let mut watched = false;
+        if let Some(watchpoints) = &mut self.machine.watchpoints {
+            for hit in watchpoints.hits.drain(..) {
+                writeln!(out, "watchpoint: {}", hit)?;
+                watched = true;
+            }
+        }
+        Ok(carry_on && !watched)
+    }
+
+    fn step_machine(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        match self.machine.step() {
+            Ok(Step::Continue) => Ok(true),
+            Ok(Step::Output(value)) => {
+                writeln!(out, "output: {}", value)?;
+                Ok(true)
+            }
+            Ok(Step::Input) => {
+                writeln!(out, "waiting for input")?;
+                Ok(false)
+            }
+            Ok(Step::Halt) => {
+                writeln!(out, "halted")?;
+                Ok(false)
+            }
+            Err(err) => {
+                writeln!(out, "fault: {}", err)?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Runs a single command. Returns false when it's time to quit.
+    fn execute(&mut self, command: Command, out: &mut impl Write) -> io::Result<bool> {
+        use Command::*;
+        match command {
+            Step(n) => {
+                for _ in 0..n {
+                    if !self.step(out)? {
+                        break;
+                    }
+                }
+            }
+            Continue => {
+                while self.step(out)? {
+                    if self.breakpoints.contains(&self.machine.pc) {
+                        writeln!(out, "breakpoint at {}", self.machine.pc)?;
+                        break;
+                    }
+                }
+            }
+            Break(addr) => {
+                self.breakpoints.insert(addr);
+            }
+            Delete(addr) => {
+                if !self.breakpoints.remove(&addr) {
+                    writeln!(out, "no breakpoint at {}", addr)?;
+                }
+            }
+            List(addr, n) => {
+                let mut addr = addr.unwrap_or(self.machine.pc);
+                for _ in 0..n {
+                    if let Err(err) = self.cells(addr, 1) {
+                        writeln!(out, "{}", err)?;
+                        break;
+                    }
+                    writeln!(out, "{}", self.describe(addr))?;
+                    addr = Instruction::decode_at(
+                        &self.machine.memory,
+                        addr,
+                        self.machine.instructions(),
+                    )
+                    .map_or(addr + 1, |instruction| instruction.next_addr());
+                }
+            }
+            Examine(addr, n) => match self.cells(addr, n) {
+                Ok(cells) => {
+                    for addr in cells {
+                        let value = self.machine.memory.get(addr).copied().unwrap_or(0);
+                        writeln!(out, "{:04}: {}", addr, value)?;
+                    }
+                }
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            WatchWrites(addr, n) => match self.cells(addr, n) {
+                Ok(cells) => self.machine.watch(cells, Watch::Writes),
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            DeleteWatch(addr) => {
+                if let Some(watchpoints) = &mut self.machine.watchpoints {
+                    watchpoints.unwatch(addr);
+                }
+            }
+            Set(addr, value) => match self.cells(addr, 1) {
+                Ok(_) => {
+                    if addr >= self.machine.memory.len() {
+                        self.machine.memory.resize(addr + 1);
+                    }
+                    self.machine.memory[addr] = value;
+                }
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            Pc(Some(pc)) => self.machine.pc = pc,
+            Pc(None) => writeln!(out, "{}", self.machine.pc)?,
+            RelativeBase(Some(rb)) => self.machine.relative_base = rb,
+            RelativeBase(None) => writeln!(out, "{}", self.machine.relative_base)?,
+            Input(values) if values.is_empty() => writeln!(out, "{:?}", self.machine.input)?,
+            Input(values) => self.machine.input.extend(values),
+            ClearInput => self.machine.input.clear(),
+            Output => writeln!(out, "{:?}", self.machine.output)?,
+            Help => write!(out, "{}", HELP)?,
+            Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Reads commands until `q` or end of input, showing the current
+    // instruction before each prompt. An empty line repeats the last command.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        let mut last = None;
+        let mut lines = input.lines();
+        loop {
+            writeln!(out, "{}", self.describe(self.machine.pc))?;
+            write!(out, "(icdb) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let command = if line.trim().is_empty() {
+                match last.clone() {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                match parse_command(&line) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        writeln!(out, "{}", err)?;
+                        continue;
+                    }
+                }
+            };
+            last = Some(command.clone());
+            if !self.execute(command, &mut out)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[test]
+fn debugger_session() {
+    let program = "3,12,4,12,1001,12,-1,12,1005,12,2,99,0";
+    let mut debugger = Debugger::new(Machine::from_mem_spec(program).unwrap());
+    let script = "\
+c
+in 2
+b 8
+c
+x 12
+set 12 1
+rb 5
+c
+d 8
+c
+out
+";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+0000: IN -> [12]
+(icdb) waiting for input
+0000: IN -> [12]
+(icdb) 0000: IN -> [12]
+(icdb) 0000: IN -> [12]
+(icdb) output: 2
+breakpoint at 8
+0008: JT [12], #2
+(icdb) 0012: 1
+0008: JT [12], #2
+(icdb) 0008: JT [12], #2
+(icdb) 0008: JT [12], #2
+(icdb) output: 1
+breakpoint at 8
+0008: JT [12], #2
+(icdb) 0008: JT [12], #2
+(icdb) halted
+0011: HLT
+(icdb) [2, 1]
+0011: HLT
+(icdb) ";
+    assert_eq!(out, expected);
+    assert_eq!(debugger.machine.relative_base, 5);
+}
+
+#[test]
+fn debugger_commands() {
+    use Command::*;
+    assert_eq!(parse_command("s"), Ok(Step(1)));
+    assert_eq!(parse_command("s 5"), Ok(Step(5)));
+    assert_eq!(parse_command("l"), Ok(List(None, 10)));
+    assert_eq!(parse_command("l 4 2"), Ok(List(Some(4), 2)));
+    assert_eq!(parse_command("in 1 -2"), Ok(Input(vec![1, -2])));
+    assert_eq!(parse_command("in"), Ok(Input(vec![])));
+    assert_eq!(parse_command("w 12 2"), Ok(WatchWrites(12, 2)));
+    assert!(parse_command("b").is_err());
+    assert!(parse_command("x foo").is_err());
+}
+
+#[test]
+fn debugger_watchpoints() {
+    let program = "3,12,4,12,1001,12,-1,12,1005,12,2,99,0";
+    let mut debugger = Debugger::new(Machine::from_mem_spec(program).unwrap());
+    let script = "in 2\nw 12\nc\nc\n";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+0000: IN -> [12]
+(icdb) 0000: IN -> [12]
+(icdb) 0000: IN -> [12]
+(icdb) watchpoint: 0000: wrote [12] 0->2
+0002: OUT [12]
+(icdb) output: 2
+watchpoint: 0004: wrote [12] 2->1
+0008: JT [12], #2
+(icdb) ";
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn debugger_rejects_wild_addresses() {
+    let mut machine = Machine::from_mem_spec("99").unwrap();
+    machine.memory_limit = 100;
+    let mut debugger = Debugger::new(machine);
+    let script = "\
+pc 18446744073709551615
+l
+s
+x 99 2
+x 18446744073709551615 2
+set 18446744073709551615 1
+set 100 1
+w 18446744073709551615 1
+pc 0
+";
+    let mut out = Vec::new();
+    debugger.repl(script.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+0000: HLT
+(icdb) 18446744073709551615: .data 0
+(icdb) address 18446744073709551615 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) fault: address 18446744073709551615 is past the memory limit of 100 at pc 18446744073709551615
+18446744073709551615: .data 0
+(icdb) address 100 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) address 18446744073709551615 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) address 18446744073709551615 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) address 100 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) address 18446744073709551615 is past the memory limit of 100
+18446744073709551615: .data 0
+(icdb) 0000: HLT
+(icdb) ";
+    assert_eq!(out, expected);
+    assert_eq!(debugger.machine.memory.len(), 1);
+    assert!(debugger.machine.watchpoints.is_none());
+}
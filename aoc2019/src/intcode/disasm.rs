@@ -0,0 +1,255 @@
+use super::isa::InstructionSet;
+use super::memory::Memory;
+use super::{parse_opcode, ArgMode, Opcode};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Instruction {
+    pub addr: usize,
+    pub opcode: Opcode,
+    // One mode per argument, unlike `parse_opcode` which always gives three.
+    pub modes: Vec<ArgMode>,
+    pub args: Vec<isize>,
+    pub mnemonic: &'static str,
+    // Which argument (if any) gets written to.
+    pub writes: Option<usize>,
+}
+
+impl Instruction {
+    // Decodes the instruction at `addr`, if there's a sensible one there
+    // in the full instruction set.
+    pub fn decode(memory: &[isize], addr: usize) -> Option<Self> {
+        Instruction::decode_words(memory.get(addr..)?, addr, Opcode::info)
+    }
+
+    // The same, but only copying the few words it needs out of a machine,
+    // and decoding them with the machine's own instructions.
+    pub fn decode_at(memory: &Memory, addr: usize, instructions: &InstructionSet) -> Option<Self> {
+        let end = memory.len().min(addr.saturating_add(4));
+        let words: Vec<isize> = (addr..end).map(|addr| memory[addr]).collect();
+        Instruction::decode_words(&words, addr, |opcode| instructions.info(opcode))
+    }
+
+    // `words` starts with the instruction at `addr`.
+    fn decode_words(
+        words: &[isize],
+        addr: usize,
+        info: impl Fn(Opcode) -> Option<(&'static str, usize, Option<usize>)>,
+    ) -> Option<Self> {
+        let code = *words.first()?;
+        let (opcode, modes) = parse_opcode(addr, code).ok()?;
+        let (mnemonic, arity, writes) = info(opcode)?;
+        let args = words.get(1..1 + arity)?.to_vec();
+        let modes = modes[..arity].to_vec();
+        if writes.is_some_and(|arg| modes[arg] == ArgMode::Immediate) {
+            return None;
+        }
+        Some(Instruction {
+            addr,
+            opcode,
+            modes,
+            args,
+            mnemonic,
+            writes,
+        })
+    }
+
+    pub fn next_addr(&self) -> usize {
+        self.addr + 1 + self.args.len()
+    }
+
+    // Where a jump goes, as long as it doesn't depend on what's in memory.
+    pub fn jump_target(&self) -> Option<usize> {
+        match self.opcode {
+            Opcode(5) | Opcode(6) if self.modes[1] == ArgMode::Immediate && self.args[1] >= 0 => {
+                Some(self.args[1] as usize)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether execution can carry on to `next_addr`. Intcode has no
+    // unconditional jump, so programs fake one with an immediate condition.
+    pub fn falls_through(&self) -> bool {
+        let constant = self.modes.first() == Some(&ArgMode::Immediate);
+        match self.opcode {
+            Opcode(99) => false,
+            Opcode(5) => !(constant && self.args[0] != 0),
+            Opcode(6) => !(constant && self.args[0] == 0),
+            _ => true,
+        }
+    }
+}
+
+struct Operand(ArgMode, isize);
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand(ArgMode::Immediate, value) => write!(f, "#{}", value),
+            Operand(ArgMode::Position, addr) => write!(f, "[{}]", addr),
+            Operand(ArgMode::Relative, offset) if offset < 0 => write!(f, "[r{}]", offset),
+            Operand(ArgMode::Relative, offset) => write!(f, "[r+{}]", offset),
+        }
+    }
+}
+
+// Prints as e.g. `ADD [r+3], #5 -> [224]`, with the written operand last.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let writes = self.writes;
+        write!(f, "{}", self.mnemonic)?;
+        let mut sep = " ";
+        for (i, (&mode, &arg)) in self.modes.iter().zip(&self.args).enumerate() {
+            if Some(i) != writes {
+                write!(f, "{}{}", sep, Operand(mode, arg))?;
+                sep = ", ";
+            }
+        }
+        if let Some(i) = writes {
+            write!(f, " -> {}", Operand(self.modes[i], self.args[i]))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Line {
+    Code(Instruction),
+    Data { addr: usize, values: Vec<isize> },
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Code(instruction) => write!(f, "{:04}: {}", instruction.addr, instruction),
+            Line::Data { addr, values } => {
+                write!(f, "{:04}: .data ", addr)?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Listing {
+    pub lines: Vec<Line>,
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// Data lines get split up so they don't run off the side of the screen.
+const DATA_PER_LINE: usize = 8;
+
+// Finds the instructions reachable from address 0, following any jumps with
+// immediate targets. Everything else is considered data.
+// Jumps through memory can't be followed, so code that's only reached that
+// way will show up as data.
+pub fn find_code(memory: &[isize]) -> BTreeMap<usize, Instruction> {
+    find_code_from(memory, &[0])
+}
+
+// The same, but starting from wherever else is known to be code too.
+pub fn find_code_from(memory: &[isize], roots: &[usize]) -> BTreeMap<usize, Instruction> {
+    let mut code = BTreeMap::new();
+    let mut covered = vec![false; memory.len()];
+    // Popped from the back, so the first root gets decoded first.
+    let mut todo: Vec<usize> = roots.iter().rev().copied().collect();
+    while let Some(addr) = todo.pop() {
+        if addr >= memory.len() || covered[addr] {
+            continue;
+        }
+        let instruction = match Instruction::decode(memory, addr) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        // Don't let instructions overlap, the first decoding wins.
+        if covered[addr..instruction.next_addr()].iter().any(|&c| c) {
+            continue;
+        }
+        for c in &mut covered[addr..instruction.next_addr()] {
+            *c = true;
+        }
+        if instruction.falls_through() {
+            todo.push(instruction.next_addr());
+        }
+        if let Some(target) = instruction.jump_target() {
+            todo.push(target);
+        }
+        code.insert(addr, instruction);
+    }
+    code
+}
+
+pub fn disassemble(memory: &[isize]) -> Listing {
+    let mut code = find_code(memory);
+    let mut lines = Vec::new();
+    let mut addr = 0;
+    while addr < memory.len() {
+        if let Some(instruction) = code.remove(&addr) {
+            addr = instruction.next_addr();
+            lines.push(Line::Code(instruction));
+            continue;
+        }
+        let start = addr;
+        while addr < memory.len() && addr - start < DATA_PER_LINE && !code.contains_key(&addr) {
+            addr += 1;
+        }
+        lines.push(Line::Data {
+            addr: start,
+            values: memory[start..addr].to_vec(),
+        });
+    }
+    Listing { lines }
+}
+
+#[test]
+fn disassemble_day5_example() {
+    let machine = super::Machine::from_mem_spec(super::BIG_TEST).unwrap();
+    let expected = "\
+0000: IN -> [21]
+0002: EQ [21], #8 -> [20]
+0006: JT [20], #22
+0009: LT #8, [21] -> [20]
+0013: JF [20], #31
+0016: JF #0, #36
+0019: .data 98, 0, 0
+0022: MUL [21], #125 -> [20]
+0026: OUT [20]
+0028: JT #1, #46
+0031: OUT #999
+0033: JT #1, #46
+0036: ADD #1000, #1 -> [20]
+0040: OUT [20]
+0042: JT #1, #46
+0045: .data 98
+0046: HLT
+";
+    assert_eq!(disassemble(&machine.memory.to_vec()).to_string(), expected);
+}
+
+#[test]
+fn disassemble_relative_operands() {
+    let machine = super::Machine::from_mem_spec("109,19,204,-34,21101,3,5,7,99").unwrap();
+    let expected = "\
+0000: ARB #19
+0002: OUT [r-34]
+0004: ADD #3, #5 -> [r+7]
+0008: HLT
+";
+    assert_eq!(disassemble(&machine.memory.to_vec()).to_string(), expected);
+}
@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+// Returned by `IoDevice::read` when there's nothing to read yet.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WouldBlock;
+
+// Whatever a machine is hooked up to. Reads that would block leave the
+// machine waiting on its input instruction, so it can be resumed later.
+pub trait IoDevice<W = isize> {
+    fn read(&mut self) -> Result<W, WouldBlock>;
+    fn write(&mut self, value: W);
+}
+
+// The pre-arranged input and collected output that `Machine::step` uses.
+#[derive(Debug, Clone, Default)]
+pub struct Queues<W = isize> {
+    pub input: VecDeque<W>,
+    pub output: Vec<W>,
+}
+
+impl<W> IoDevice<W> for Queues<W> {
+    fn read(&mut self) -> Result<W, WouldBlock> {
+        self.input.pop_front().ok_or(WouldBlock)
+    }
+
+    fn write(&mut self, value: W) {
+        self.output.push(value);
+    }
+}
+
+#[test]
+fn run_with_device() {
+    use super::{asm::assemble, Machine, Step};
+
+    // Keeps feeding the machine's output back into it until it gets big.
+    struct Feedback {
+        next: Option<isize>,
+        seen: Vec<isize>,
+    }
+
+    impl IoDevice for Feedback {
+        fn read(&mut self) -> Result<isize, WouldBlock> {
+            self.next.take().ok_or(WouldBlock)
+        }
+
+        fn write(&mut self, value: isize) {
+            self.seen.push(value);
+            if value < 100 {
+                self.next = Some(value);
+            }
+        }
+    }
+
+    let program = assemble(
+        "
+        loop:
+            in [x]
+            mul [x], #2 -> [x]
+            out [x]
+            jt [x], #loop
+            hlt
+        x:  .data 0
+        ",
+    )
+    .unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    let mut device = Feedback {
+        next: Some(1),
+        seen: Vec::new(),
+    };
+    assert_eq!(machine.run_with(&mut device), Ok(Step::Input));
+    assert_eq!(&device.seen, &[2, 4, 8, 16, 32, 64, 128]);
+
+    // Blocking leaves the machine ready to pick up where it left off.
+    device.next = Some(0);
+    assert_eq!(machine.run_with(&mut device), Ok(Step::Halt));
+    assert_eq!(device.seen.last(), Some(&0));
+    assert!(machine.output.is_empty());
+}
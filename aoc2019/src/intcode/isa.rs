@@ -0,0 +1,321 @@
+// Which opcodes a machine understands. The puzzles added instructions as
+// they went along, so older programs can be run under the rules they were
+// written for, and experimental opcodes can be bolted on.
+use super::io::IoDevice;
+use super::{InstructionImpl, Machine, MachineError, Modes, Opcode, Step, Word};
+use std::fmt;
+
+// Computes the written operand from the others, in order, or says why it
+// can't.
+pub type Custom<W> = fn(&[W]) -> Result<W, &'static str>;
+
+enum Implementation<W: Word> {
+    Builtin(InstructionImpl<W>),
+    Custom(Custom<W>),
+}
+
+pub struct Definition<W: Word> {
+    pub mnemonic: &'static str,
+    pub arity: usize,
+    // Which argument (if any) gets written to.
+    pub writes: Option<usize>,
+    implementation: Implementation<W>,
+}
+
+// Not derived, for the same reason as `Decoded`.
+impl<W: Word> Clone for Implementation<W> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W: Word> Copy for Implementation<W> {}
+
+impl<W: Word> Clone for Definition<W> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W: Word> Copy for Definition<W> {}
+
+impl<W: Word> Definition<W> {
+    // Custom instructions only ever compute, so anything that does I/O
+    // isn't one.
+    pub fn is_custom(&self) -> bool {
+        matches!(self.implementation, Implementation::Custom(_))
+    }
+}
+
+fn builtin<W: Word>(opcode: Opcode) -> Option<InstructionImpl<W>> {
+    Some(match opcode.0 {
+        1 => Machine::add as InstructionImpl<W>,
+        2 => Machine::mul,
+        3 => Machine::input,
+        4 => Machine::output,
+        5 => Machine::jump_if_true,
+        6 => Machine::jump_if_false,
+        7 => Machine::less_than,
+        8 => Machine::equals,
+        9 => Machine::adjust_relative_base,
+        99 => Machine::halt,
+        _ => return None,
+    })
+}
+
+// Indexed by opcode, which only ever has two digits.
+#[derive(Clone)]
+pub struct InstructionSet<W: Word = isize> {
+    definitions: Vec<Option<Definition<W>>>,
+}
+
+impl<W: Word> InstructionSet<W> {
+    fn with_opcodes(opcodes: &[usize]) -> Self {
+        let mut definitions = vec![None; 100];
+        for &opcode in opcodes {
+            let (mnemonic, arity, writes) = Opcode(opcode).info().unwrap();
+            let implementation = Implementation::Builtin(builtin(Opcode(opcode)).unwrap());
+            definitions[opcode] = Some(Definition {
+                mnemonic,
+                arity,
+                writes,
+                implementation,
+            });
+        }
+        InstructionSet { definitions }
+    }
+
+    // Just arithmetic, as on day 2.
+    pub fn day2() -> Self {
+        InstructionSet::with_opcodes(&[1, 2, 99])
+    }
+
+    // Adds I/O, jumps and comparisons.
+    pub fn day5() -> Self {
+        InstructionSet::with_opcodes(&[1, 2, 3, 4, 5, 6, 7, 8, 99])
+    }
+
+    // Everything, once day 9 added the relative base.
+    pub fn full() -> Self {
+        InstructionSet::with_opcodes(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 99])
+    }
+
+    // One of `day2`, `day5` or `day9-full`.
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "day2" => Some(InstructionSet::day2()),
+            "day5" => Some(InstructionSet::day5()),
+            "day9-full" => Some(InstructionSet::full()),
+            _ => None,
+        }
+    }
+
+    // The opposite of `named`, or `None` for anything with custom opcodes.
+    pub fn name(&self) -> Option<&'static str> {
+        ["day2", "day5", "day9-full"]
+            .iter()
+            .copied()
+            .find(|&name| self.same_as(&InstructionSet::named(name).unwrap()))
+    }
+
+    pub fn get(&self, opcode: Opcode) -> Option<&Definition<W>> {
+        self.definitions.get(opcode.0)?.as_ref()
+    }
+
+    // Like `Opcode::info`, but for this set.
+    pub fn info(&self, opcode: Opcode) -> Option<(&'static str, usize, Option<usize>)> {
+        let definition = self.get(opcode)?;
+        Some((definition.mnemonic, definition.arity, definition.writes))
+    }
+
+    // Whether this is exactly the full set, which is what the static
+    // analyses (and so the compiler) assume.
+    pub fn is_full(&self) -> bool {
+        self.same_as(&InstructionSet::full())
+    }
+
+    // Whether this has exactly the opcodes `profile` does, none of them
+    // custom. Profiles never have anything custom in them to begin with.
+    fn same_as(&self, profile: &Self) -> bool {
+        self.definitions
+            .iter()
+            .zip(&profile.definitions)
+            .all(|pair| match pair {
+                (Some(ours), Some(_)) => matches!(ours.implementation, Implementation::Builtin(_)),
+                (ours, theirs) => ours.is_none() && theirs.is_none(),
+            })
+    }
+
+    // Adds an instruction that reads all its arguments except `writes`,
+    // and writes `semantics` of them there. Without `writes` the result is
+    // only checked for errors. Replaces whatever `opcode` already was.
+    pub fn register(
+        &mut self,
+        opcode: Opcode,
+        mnemonic: &'static str,
+        arity: usize,
+        writes: Option<usize>,
+        semantics: Custom<W>,
+    ) {
+        // Modes only go up to three arguments, see `parse_opcode`.
+        assert!(opcode.0 < 100, "opcodes only have two digits");
+        assert!(
+            writes.is_none_or(|writes| writes < arity) && arity <= 3,
+            "bad arguments for {}",
+            mnemonic
+        );
+        self.definitions[opcode.0] = Some(Definition {
+            mnemonic,
+            arity,
+            writes,
+            implementation: Implementation::Custom(semantics),
+        });
+    }
+
+    pub(super) fn implementation(&self, opcode: Opcode) -> Option<InstructionImpl<W>> {
+        Some(match self.get(opcode)?.implementation {
+            Implementation::Builtin(implementation) => implementation,
+            Implementation::Custom(_) => Machine::custom,
+        })
+    }
+}
+
+impl<W: Word> Default for InstructionSet<W> {
+    fn default() -> Self {
+        InstructionSet::full()
+    }
+}
+
+// Prints as e.g. `{1: ADD, 2: MUL, 99: HLT}`.
+impl<W: Word> fmt::Debug for InstructionSet<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonics = (self.definitions.iter().enumerate())
+            .filter_map(|(opcode, definition)| Some((opcode, definition.as_ref()?.mnemonic)));
+        f.debug_map().entries(mnemonics).finish()
+    }
+}
+
+impl<W: Word> Machine<W> {
+    fn custom(&mut self, _: &mut dyn IoDevice<W>, modes: Modes) -> Result<Step<W>, MachineError> {
+        let opcode = Opcode((self.memory[self.pc].saturating_isize() % 100) as usize);
+        let definition = *self.instructions.get(opcode).unwrap();
+        let semantics = match definition.implementation {
+            Implementation::Custom(semantics) => semantics,
+            _ => unreachable!("{} isn't custom", definition.mnemonic),
+        };
+        let mut args = Vec::with_capacity(definition.arity);
+        for i in (0..definition.arity).filter(|&i| Some(i) != definition.writes) {
+            args.push(self.read(self.pc + 1 + i, modes[i])?);
+        }
+        let result = semantics(&args).map_err(|reason| MachineError::Custom {
+            pc: self.pc,
+            mnemonic: definition.mnemonic,
+            reason,
+        })?;
+        if let Some(writes) = definition.writes {
+            self.write(self.pc + 1 + writes, result, modes[writes])?;
+        }
+        self.pc += 1 + definition.arity;
+        Ok(Step::Continue)
+    }
+}
+
+#[test]
+fn profiles_restrict_opcodes() {
+    // Outputs 3, which day 2 didn't know how to do.
+    let program = "1101,1,2,7,4,7,99,0";
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    machine.set_instructions(InstructionSet::named("day2").unwrap());
+    assert_eq!(
+        machine.run(),
+        Err(MachineError::UnknownOpcode { pc: 4, code: 4 })
+    );
+
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    machine.set_instructions(InstructionSet::named("day5").unwrap());
+    machine.run().unwrap();
+    assert_eq!(machine.output, vec![3]);
+
+    let full: InstructionSet = InstructionSet::named("day9-full").unwrap();
+    assert!(full.is_full());
+    assert!(!InstructionSet::<isize>::day5().is_full());
+    assert!(InstructionSet::<isize>::named("day12").is_none());
+    assert_eq!(full.name(), Some("day9-full"));
+    assert_eq!(InstructionSet::<isize>::day2().name(), Some("day2"));
+}
+
+#[test]
+fn custom_opcodes() {
+    let mut instructions = InstructionSet::full();
+    // Integer division, with the destination first for a change.
+    instructions.register(Opcode(10), "DIV", 3, Some(0), |args: &[isize]| {
+        args[0].checked_div(args[1]).ok_or("division by zero")
+    });
+    // Faults unless its argument is zero.
+    instructions.register(Opcode(11), "CHZ", 1, None, |args| match args[0] {
+        0 => Ok(0),
+        _ => Err("not zero"),
+    });
+    assert!(!instructions.is_full());
+    assert_eq!(instructions.name(), None);
+
+    let mut machine = Machine::from_mem_spec("11010,7,17,5,4,7,99,0").unwrap();
+    machine.set_instructions(instructions.clone());
+    machine.run().unwrap();
+    assert_eq!(machine.output, vec![3]);
+
+    let mut machine = Machine::from_mem_spec("11010,7,5,0,99").unwrap();
+    machine.set_instructions(instructions.clone());
+    assert_eq!(
+        machine.run().unwrap_err().to_string(),
+        "DIV failed at pc 0: division by zero"
+    );
+
+    let mut machine = Machine::from_mem_spec("111,0,111,1,99").unwrap();
+    machine.set_instructions(instructions.clone());
+    assert_eq!(
+        machine.run(),
+        Err(MachineError::Custom {
+            pc: 2,
+            mnemonic: "CHZ",
+            reason: "not zero"
+        })
+    );
+
+    // Forks keep their instruction set.
+    let mut machine = Machine::from_mem_spec("11010,7,17,5,99").unwrap();
+    machine.set_instructions(instructions);
+    let mut fork = machine.clone();
+    fork.run().unwrap();
+    assert_eq!(fork.memory[7], 3);
+}
+
+#[test]
+fn custom_opcodes_are_traced_and_decoded() {
+    use super::disasm::Instruction;
+    use super::trace::{Replay, Trace};
+
+    let mut instructions = InstructionSet::full();
+    instructions.register(Opcode(10), "DIV", 3, Some(0), |args: &[isize]| {
+        args[0].checked_div(args[1]).ok_or("division by zero")
+    });
+
+    // Divides itself by itself, growing memory to fetch its operands.
+    let mut machine = Machine::from_mem_spec("10").unwrap();
+    machine.set_instructions(instructions.clone());
+    machine.trace = Some(Trace::default());
+    let initial = machine.clone();
+    machine.step().unwrap();
+    let trace = machine.trace.take().unwrap();
+    assert_eq!(
+        trace.entries[0].to_string(),
+        "0000: DIV [0]=10 [0]=10 [0]:10->1"
+    );
+    let mut replay = Replay::new(initial, &trace);
+    assert_eq!(replay.seek(1).memory, machine.memory);
+
+    let mut machine = Machine::from_mem_spec("11010,7,17,5").unwrap();
+    machine.set_instructions(instructions);
+    let instruction = Instruction::decode_at(&machine.memory, 0, machine.instructions());
+    assert_eq!(instruction.unwrap().to_string(), "DIV #17, #5 -> [7]");
+}
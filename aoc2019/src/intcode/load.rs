@@ -0,0 +1,236 @@
+// Reading programs from files, in either of two formats.
+//
+// Text is the usual comma-separated words, except that whitespace
+// (newlines included) separates them too, and `;` starts a comment that
+// runs to the end of the line. Two commas still need a word between them:
+//
+//     3,225,1,225,6,6   ; read the system ID
+//     1100,1,238,225
+//
+// Binary is more compact, since most words are small:
+//
+//     magic    "ICPROG"
+//     version  u8, currently 1
+//     words    zigzag-encoded LEB128 varints, up to the end of the file
+use super::Machine;
+use derive_more::Display;
+use std::io::{self, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 6] = b"ICPROG";
+const VERSION: u8 = 1;
+
+// `file` is whatever name the program was loaded under, for messages.
+// Lines, columns and offsets all count from 1.
+#[derive(Debug, Display)]
+pub enum LoadError {
+    #[display(fmt = "{}: {}", file, err)]
+    Io { file: String, err: io::Error },
+    #[display(fmt = "{}:{}:{}: not a number: {:?}", file, line, column, token)]
+    Parse {
+        file: String,
+        line: usize,
+        column: usize,
+        token: String,
+    },
+    #[display(fmt = "{}: not a binary Intcode program", file)]
+    BadMagic { file: String },
+    #[display(fmt = "{}: unsupported program version {}", file, version)]
+    UnsupportedVersion { file: String, version: u8 },
+    #[display(fmt = "{}: bad word at byte {}", file, offset)]
+    BadVarint { file: String, offset: usize },
+}
+
+impl std::error::Error for LoadError {}
+
+pub fn parse_text(file: &str, src: &str) -> Result<Vec<isize>, LoadError> {
+    let mut words = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let error = |column, token: &str| LoadError::Parse {
+            file: file.to_string(),
+            line: i + 1,
+            column,
+            token: token.to_string(),
+        };
+        let code = line.split(';').next().unwrap();
+        let fields: Vec<&str> = code.split(',').collect();
+        let mut column = 1;
+        for (j, field) in fields.iter().enumerate() {
+            let mut token_column = column;
+            let mut empty = true;
+            for token in field.split(char::is_whitespace) {
+                if !token.is_empty() {
+                    words.push(token.parse().map_err(|_| error(token_column, token))?);
+                    empty = false;
+                }
+                // Skip past the token and the whitespace after it.
+                token_column += token.chars().count() + 1;
+            }
+            // Only the last field on a line can be empty, after a trailing
+            // comma. Anywhere else a number is missing.
+            if empty && j + 1 < fields.len() {
+                return Err(error(column, ""));
+            }
+            column += field.chars().count() + 1;
+        }
+    }
+    Ok(words)
+}
+
+pub fn write_binary(mut w: impl Write, words: &[isize]) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    for &word in words {
+        // Zigzag encoding keeps small negative numbers small too.
+        let mut n = ((word << 1) ^ (word >> (isize::BITS - 1))) as usize;
+        while n >= 0x80 {
+            w.write_all(&[(n as u8) | 0x80])?;
+            n >>= 7;
+        }
+        w.write_all(&[n as u8])?;
+    }
+    Ok(())
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn parse_binary(file: &str, bytes: &[u8]) -> Result<Vec<isize>, LoadError> {
+    if !is_binary(bytes) {
+        return Err(LoadError::BadMagic {
+            file: file.to_string(),
+        });
+    }
+    let version = bytes.get(MAGIC.len()).copied().unwrap_or(0);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion {
+            file: file.to_string(),
+            version,
+        });
+    }
+    let mut words = Vec::new();
+    let mut offset = MAGIC.len() + 1;
+    while offset < bytes.len() {
+        let start = offset;
+        let bad = || LoadError::BadVarint {
+            file: file.to_string(),
+            offset: start + 1,
+        };
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.get(offset).ok_or_else(bad)?;
+            offset += 1;
+            let bits = (byte & 0x7f) as usize;
+            // Anything that doesn't fit in a word.
+            if shift >= usize::BITS || bits.leading_zeros() < shift {
+                return Err(bad());
+            }
+            n |= bits << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        words.push((n >> 1) as isize ^ -((n & 1) as isize));
+    }
+    Ok(words)
+}
+
+impl Machine {
+    // Loads a program in either format, telling them apart by the magic.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+        let bytes = std::fs::read(path).map_err(|err| LoadError::Io {
+            file: file.clone(),
+            err,
+        })?;
+        let words = if is_binary(&bytes) {
+            parse_binary(&file, &bytes)?
+        } else {
+            let src = String::from_utf8(bytes).map_err(|err| LoadError::Io {
+                file: file.clone(),
+                err: io::Error::new(io::ErrorKind::InvalidData, err),
+            })?;
+            parse_text(&file, &src)?
+        };
+        Ok(Machine::from_words(words))
+    }
+}
+
+#[test]
+fn text_is_tolerant() {
+    let src = "
+        ; Outputs its input.
+        3,5 ,4, 5   ; in, out
+        99
+        0,
+    ";
+    assert_eq!(parse_text("echo", src).unwrap(), vec![3, 5, 4, 5, 99, 0]);
+
+    let err = parse_text("echo", "3,5\n4,x5 ; oops").unwrap_err();
+    assert_eq!(err.to_string(), "echo:2:3: not a number: \"x5\"");
+    let err = parse_text("echo", "  1 ,  2,\t3,-").unwrap_err();
+    assert_eq!(err.to_string(), "echo:1:13: not a number: \"-\"");
+    let err = parse_text("echo", "3,5\n4,,5").unwrap_err();
+    assert_eq!(err.to_string(), "echo:2:3: not a number: \"\"");
+    let err = parse_text("echo", "3, \t,5").unwrap_err();
+    assert_eq!(err.to_string(), "echo:1:3: not a number: \"\"");
+}
+
+#[test]
+fn binary_round_trips() {
+    let words = vec![0, 1, -1, 63, -64, 64, 1102, isize::MAX, isize::MIN];
+    let mut bytes = Vec::new();
+    write_binary(&mut bytes, &words).unwrap();
+    assert_eq!(parse_binary("words", &bytes).unwrap(), words);
+    // The small ones are one byte each.
+    assert_eq!(&bytes[7..12], &[0, 2, 1, 126, 127]);
+
+    let truncated = parse_binary("words", &bytes[..bytes.len() - 1]).unwrap_err();
+    assert_eq!(truncated.to_string(), "words: bad word at byte 27");
+    let err = parse_binary("words", b"ICPROG\x02").unwrap_err();
+    assert_eq!(err.to_string(), "words: unsupported program version 2");
+    let err = parse_binary("words", b"3,5,4,5,99").unwrap_err();
+    assert_eq!(err.to_string(), "words: not a binary Intcode program");
+    // Eleven bytes is more than any word needs.
+    let err = parse_binary(
+        "words",
+        b"ICPROG\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+    );
+    assert!(matches!(err, Err(LoadError::BadVarint { offset: 8, .. })));
+}
+
+#[test]
+fn programs_load_from_files() {
+    let dir = std::env::temp_dir();
+    let text = dir.join(format!("intcode-{}.txt", std::process::id()));
+    let binary = dir.join(format!("intcode-{}.bin", std::process::id()));
+    std::fs::write(&text, "3,5,4,5,99 ; echo\n").unwrap();
+    let mut bytes = Vec::new();
+    write_binary(&mut bytes, &[3, 5, 4, 5, 99]).unwrap();
+    std::fs::write(&binary, bytes).unwrap();
+
+    for path in &[&text, &binary] {
+        let mut machine = Machine::from_file(path).unwrap();
+        machine.input.push_back(7);
+        machine.run().unwrap();
+        assert_eq!(machine.output, vec![7]);
+    }
+
+    std::fs::write(&text, "3,5,4,5\n99,zero\n").unwrap();
+    let err = Machine::from_file(&text).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        format!("{}:2:4: not a number: \"zero\"", text.display())
+    );
+
+    std::fs::remove_file(&text).unwrap();
+    std::fs::remove_file(&binary).unwrap();
+    assert!(matches!(
+        Machine::from_file(&text),
+        Err(LoadError::Io { .. })
+    ));
+}
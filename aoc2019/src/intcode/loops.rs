@@ -0,0 +1,198 @@
+// Spotting programs that will never halt. Execution is deterministic
+// between I/O, so if the machine gets back to a state it has already been in
+// without doing any I/O, it's going to go round the same way forever.
+use super::memory::{Memory, Page};
+use super::{Machine, MachineError, Step, Stop, Word};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// Everything that decides what a machine does next. Keeping the memory
+// only costs a pointer per page, the same as a fork.
+struct State<W: Word> {
+    pc: usize,
+    relative_base: isize,
+    input: VecDeque<W>,
+    memory: Memory<W>,
+}
+
+impl<W: Word> State<W> {
+    fn of(machine: &Machine<W>) -> Self {
+        State {
+            pc: machine.pc,
+            relative_base: machine.relative_base,
+            input: machine.input.clone(),
+            memory: machine.memory.clone(),
+        }
+    }
+
+    // Pages both still share compare by pointer, so this is cheap unless
+    // the states really are close.
+    fn matches(&self, machine: &Machine<W>) -> bool {
+        self.pc == machine.pc
+            && self.relative_base == machine.relative_base
+            && self.input == machine.input
+            && self.memory == machine.memory
+    }
+}
+
+// States are only hashed at backward jumps, since every loop has one and
+// they're much rarer than instructions in general.
+pub struct LoopDetector<W: Word = isize> {
+    // The states seen since the last I/O, by hash. Hashes can collide, so
+    // they only narrow down which states need comparing.
+    seen: HashMap<u64, Vec<State<W>>>,
+    input_len: usize,
+    // Each page as of the last check and its hash, so only pages written
+    // since then need hashing again.
+    pages: HashMap<usize, (Page<W>, u64)>,
+}
+
+impl<W: Word> LoopDetector<W> {
+    pub fn new() -> Self {
+        LoopDetector {
+            seen: HashMap::new(),
+            input_len: 0,
+            pages: HashMap::new(),
+        }
+    }
+
+    // Call after every step, with the pc from before it. Returns whether
+    // the machine has been in exactly this state before, with no I/O since.
+    pub fn check(&mut self, machine: &Machine<W>, old_pc: usize, step: &Step<W>) -> bool {
+        if let Step::Output(_) = step {
+            self.seen.clear();
+        }
+        if machine.input.len() != self.input_len {
+            self.input_len = machine.input.len();
+            self.seen.clear();
+        }
+        if machine.pc > old_pc {
+            return false;
+        }
+        let hash = self.digest(machine);
+        let states = self.seen.entry(hash).or_default();
+        if states.iter().any(|state| state.matches(machine)) {
+            return true;
+        }
+        states.push(State::of(machine));
+        false
+    }
+
+    fn digest(&mut self, machine: &Machine<W>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        machine.pc.hash(&mut hasher);
+        machine.relative_base.hash(&mut hasher);
+        machine.input.len().hash(&mut hasher);
+        machine.memory.len().hash(&mut hasher);
+        for (number, page) in machine.memory.pages() {
+            let hash = match self.pages.get(&number) {
+                Some((seen, hash)) if Arc::ptr_eq(seen, page) => *hash,
+                _ => {
+                    let mut page_hasher = DefaultHasher::new();
+                    page.hash(&mut page_hasher);
+                    let hash = page_hasher.finish();
+                    self.pages.insert(number, (page.clone(), hash));
+                    hash
+                }
+            };
+            (number, hash).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+impl<W: Word> Default for LoopDetector<W> {
+    fn default() -> Self {
+        LoopDetector::new()
+    }
+}
+
+impl<W: Word> Machine<W> {
+    // Runs like `run`, but stops with `Stop::Looped` instead of spinning
+    // forever.
+    pub fn run_detecting_loops(&mut self) -> Result<Stop, MachineError> {
+        use Step::*;
+        let mut detector = LoopDetector::new();
+        loop {
+            let old_pc = self.pc;
+            let step = self.step()?;
+            match step {
+                Output(_) | Continue => (),
+                Halt => return Ok(Stop::Halted),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
+            }
+            if detector.check(self, old_pc, &step) {
+                return Ok(Stop::Looped { pc: self.pc });
+            }
+        }
+    }
+}
+
+#[test]
+fn loops_are_detected() {
+    use super::asm::assemble;
+
+    let run = |src: &str| {
+        let mut machine = Machine::from_mem_spec(&assemble(src).unwrap()).unwrap();
+        machine.run_detecting_loops()
+    };
+    assert_eq!(run("loop: jt #1, #loop"), Ok(Stop::Looped { pc: 0 }));
+    // Memory keeps changing, but only between two states.
+    assert_eq!(
+        run("
+            loop:
+                mul [x], #-1 -> [x]
+                jt #1, #loop
+            x:  .data 1
+            "),
+        Ok(Stop::Looped { pc: 0 })
+    );
+    // Looping a lot isn't the same as looping forever.
+    assert_eq!(
+        run("
+            loop:
+                add [n], #-1 -> [n]
+                jt [n], #loop
+                hlt
+            n:  .data 1000
+            "),
+        Ok(Stop::Halted)
+    );
+}
+
+#[test]
+fn io_resets_loop_detection() {
+    use super::asm::assemble;
+
+    // Outputs the same thing forever, which isn't stuck as far as whoever
+    // is reading it is concerned.
+    let program = assemble("loop: out #7\njt #1, #loop").unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    let mut detector = LoopDetector::new();
+    for _ in 0..1000 {
+        let old_pc = machine.pc;
+        let step = machine.step().unwrap();
+        assert!(!detector.check(&machine, old_pc, &step));
+    }
+}
+
+#[test]
+fn hash_collisions_are_not_loops() {
+    use super::asm::assemble;
+
+    let program = assemble("loop: jt #1, #loop").unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    let mut detector = LoopDetector::new();
+    // Plants some other state under the hash the machine is about to have.
+    let mut other = machine.clone();
+    other.memory[1] = 2;
+    let hash = detector.digest(&machine);
+    detector.seen.insert(hash, vec![State::of(&other)]);
+
+    let step = machine.step().unwrap();
+    assert!(!detector.check(&machine, 0, &step));
+    let step = machine.step().unwrap();
+    assert!(detector.check(&machine, 0, &step));
+}
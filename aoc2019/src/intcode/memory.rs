@@ -0,0 +1,284 @@
+// Copy-on-write paged storage, so cloning a machine only costs a pointer
+// per page, and a fork only copies the pages it actually writes to.
+//
+// Low pages sit in a vector for speed. Anything past that lives in a map and
+// is only allocated once written to, so a program poking at address 10^9
+// costs one page rather than gigabytes.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+const PAGE_BITS: usize = 8;
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: usize = PAGE_SIZE - 1;
+// A quarter of a million words, which every real program fits inside.
+const DENSE_PAGES: usize = 1024;
+
+// Behaves like a `Vec<T>` as far as indexing and resizing go, where anything
+// never written to reads as `T::default()`.
+//
+// Every slot past `len` in the last page is kept at `T::default()`, so
+// growing never has to go back and clean up.
+#[derive(Clone)]
+pub struct Paged<T> {
+    dense: Vec<Page<T>>,
+    sparse: BTreeMap<usize, Page<T>>,
+    // Shared by every page nobody has written to yet.
+    blank: Page<T>,
+    len: usize,
+}
+
+// A fixed size, so indexing into a page needs neither a bounds check nor a
+// second pointer to chase.
+pub type Page<T> = Arc<[T; PAGE_SIZE]>;
+
+pub type Memory<W = isize> = Paged<W>;
+
+impl<T: Clone + Default> Paged<T> {
+    pub fn new() -> Self {
+        Paged {
+            dense: Vec::new(),
+            sparse: BTreeMap::new(),
+            blank: Arc::new(std::array::from_fn(|_| T::default())),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn page(&self, page: usize) -> &[T; PAGE_SIZE] {
+        if page < self.dense.len() {
+            &self.dense[page]
+        } else {
+            self.sparse.get(&page).unwrap_or(&self.blank)
+        }
+    }
+
+    // Copies the page first if anyone else is looking at it.
+    #[inline]
+    fn page_mut(&mut self, page: usize) -> &mut [T; PAGE_SIZE] {
+        if page < self.dense.len() {
+            Arc::make_mut(&mut self.dense[page])
+        } else {
+            let blank = &self.blank;
+            let page = self.sparse.entry(page).or_insert_with(|| blank.clone());
+            Arc::make_mut(page)
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, addr: usize) -> Option<&T> {
+        if addr < self.len {
+            Some(&self.page(addr >> PAGE_BITS)[addr & PAGE_MASK])
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, addr: usize) -> Option<&mut T> {
+        if addr < self.len {
+            Some(&mut self.page_mut(addr >> PAGE_BITS)[addr & PAGE_MASK])
+        } else {
+            None
+        }
+    }
+
+    // Grows with `T::default()`, which costs nothing until written to.
+    pub fn resize(&mut self, len: usize) {
+        let pages = len.div_ceil(PAGE_SIZE);
+        self.dense.truncate(pages);
+        while self.dense.len() < pages.min(DENSE_PAGES) {
+            self.dense.push(self.blank.clone());
+        }
+        self.sparse.split_off(&pages);
+        // Keep the invariant when shrinking part way into a page.
+        if len < self.len && len & PAGE_MASK != 0 {
+            for slot in &mut self.page_mut(len >> PAGE_BITS)[len & PAGE_MASK..] {
+                *slot = T::default();
+            }
+        }
+        self.len = len;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..self.len.div_ceil(PAGE_SIZE))
+            .flat_map(move |page| self.page(page).iter())
+            .take(self.len)
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    // How many pages have storage of their own, rather than being blank.
+    pub fn allocated_pages(&self) -> usize {
+        self.dense
+            .iter()
+            .chain(self.sparse.values())
+            .filter(|page| !Arc::ptr_eq(page, &self.blank))
+            .count()
+    }
+
+    // How many pages are currently shared with some other clone.
+    pub fn shared_pages(&self) -> usize {
+        self.dense
+            .iter()
+            .chain(self.sparse.values())
+            .filter(|page| Arc::strong_count(page) > 1)
+            .count()
+    }
+
+    // Every page with its page number, blank ones included. Holding on to a
+    // page means any later write to it goes to a fresh copy, so comparing
+    // pointers is enough to tell whether it has changed.
+    pub fn pages(&self) -> impl Iterator<Item = (usize, &Page<T>)> + '_ {
+        self.dense
+            .iter()
+            .enumerate()
+            .chain(self.sparse.iter().map(|(&page, contents)| (page, contents)))
+    }
+}
+
+impl<T: Clone + Default> Default for Paged<T> {
+    fn default() -> Self {
+        Paged::new()
+    }
+}
+
+impl<T: Clone + Default> From<Vec<T>> for Paged<T> {
+    fn from(values: Vec<T>) -> Self {
+        let mut paged = Paged::new();
+        paged.resize(values.len());
+        for (page, chunk) in values.chunks(PAGE_SIZE).enumerate() {
+            paged.page_mut(page)[..chunk.len()].clone_from_slice(chunk);
+        }
+        paged
+    }
+}
+
+impl<T: Clone + Default> Index<usize> for Paged<T> {
+    type Output = T;
+
+    #[inline]
+    fn index(&self, addr: usize) -> &T {
+        let len = self.len;
+        self.get(addr)
+            .unwrap_or_else(|| panic!("address {} out of range for length {}", addr, len))
+    }
+}
+
+impl<T: Clone + Default> IndexMut<usize> for Paged<T> {
+    #[inline]
+    fn index_mut(&mut self, addr: usize) -> &mut T {
+        let len = self.len;
+        self.get_mut(addr)
+            .unwrap_or_else(|| panic!("address {} out of range for length {}", addr, len))
+    }
+}
+
+impl<T: Clone + Default + fmt::Debug> fmt::Debug for Paged<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// Page by page, so pages the two still share don't need looking at.
+impl<T: Clone + Default + PartialEq> PartialEq for Paged<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let same = |ours: &Page<T>, theirs: &Page<T>| Arc::ptr_eq(ours, theirs) || ours == theirs;
+        // A sparse page missing from one side is blank there.
+        let sparse = |ours: &Self, theirs: &Self| {
+            (ours.sparse.iter()).all(|(page, contents)| {
+                same(contents, theirs.sparse.get(page).unwrap_or(&theirs.blank))
+            })
+        };
+        self.len == other.len
+            && self
+                .dense
+                .iter()
+                .zip(&other.dense)
+                .all(|(ours, theirs)| same(ours, theirs))
+            && sparse(self, other)
+            && sparse(other, self)
+    }
+}
+
+impl<T: Clone + Default + Eq> Eq for Paged<T> {}
+
+impl<T: Clone + Default + PartialEq> PartialEq<[T]> for Paged<T> {
+    fn eq(&self, other: &[T]) -> bool {
+        self.len == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Clone + Default + PartialEq, const N: usize> PartialEq<[T; N]> for Paged<T> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        *self == other[..]
+    }
+}
+
+impl<T: Clone + Default + PartialEq> PartialEq<Vec<T>> for Paged<T> {
+    fn eq(&self, other: &Vec<T>) -> bool {
+        *self == other[..]
+    }
+}
+
+#[test]
+fn paged_behaves_like_vec() {
+    let mut memory = Memory::from((0..600).collect::<Vec<_>>());
+    assert_eq!(memory.len(), 600);
+    assert_eq!(memory[599], 599);
+    assert_eq!(memory.get(600), None);
+
+    memory.resize(300);
+    assert_eq!(memory, (0..300).collect::<Vec<_>>());
+    // Growing again brings back zeroes, not the old values.
+    memory.resize(1000);
+    assert_eq!(memory[299], 299);
+    assert_eq!(memory[300], 0);
+    assert_eq!(memory[599], 0);
+}
+
+#[test]
+fn paged_copies_on_write() {
+    let original = Memory::from(vec![1; 4 * PAGE_SIZE]);
+    let mut fork = original.clone();
+    assert_eq!(fork.shared_pages(), 4);
+
+    fork[PAGE_SIZE + 3] = 5;
+    assert_eq!(fork.shared_pages(), 3);
+    assert_eq!(original.shared_pages(), 3);
+    assert_eq!(original[PAGE_SIZE + 3], 1);
+    assert_eq!(fork[PAGE_SIZE + 3], 5);
+}
+
+#[test]
+fn paged_is_sparse() {
+    let mut memory = Memory::from(vec![1, 2, 3]);
+    memory.resize(1_000_000_001);
+    assert_eq!(memory.allocated_pages(), 1);
+    memory[1_000_000_000] = 4;
+    memory[DENSE_PAGES * PAGE_SIZE - 1] = 5;
+    assert_eq!(memory.allocated_pages(), 3);
+    assert_eq!(memory[999_999_999], 0);
+    assert_eq!(memory[1_000_000_000], 4);
+
+    memory.resize(10);
+    assert_eq!(memory.allocated_pages(), 1);
+    memory.resize(1_000_000_001);
+    assert_eq!(memory[1_000_000_000], 0);
+
+    // Written to and back again is the same as never written to.
+    let mut written = memory.clone();
+    written[1_000_000_000] = 1;
+    assert_ne!(written, memory);
+    written[1_000_000_000] = 0;
+    assert_eq!(written.allocated_pages(), 2);
+    assert_eq!(written, memory);
+    assert_eq!(memory, written);
+}
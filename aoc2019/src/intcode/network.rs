@@ -0,0 +1,244 @@
+// A network of machines that talk in packets, as in 2019 day 23.
+//
+// Each machine is told its address on boot, then sends packets by
+// outputting a destination address followed by X and Y, and receives them
+// by reading X and Y. Reading with nothing queued gives -1.
+//
+// Packets sent to `NAT_ADDRESS` go to the NAT, which holds on to the last
+// one it got. When the whole network goes quiet the NAT sends it to
+// address 0 to get things moving again.
+use super::io::{IoDevice, WouldBlock};
+use super::runtime::{Scheduler, TaskId};
+use super::{Machine, MachineError};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+pub const NAT_ADDRESS: isize = 255;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Packet {
+    pub dest: isize,
+    pub x: isize,
+    pub y: isize,
+}
+
+#[derive(Debug, Default)]
+struct Router {
+    queues: Vec<VecDeque<isize>>,
+    nat: Option<Packet>,
+    nat_received: Vec<Packet>,
+    nat_sent: Vec<Packet>,
+    dropped: Vec<Packet>,
+    routed: usize,
+}
+
+impl Router {
+    fn route(&mut self, packet: Packet) {
+        self.routed += 1;
+        if packet.dest == NAT_ADDRESS {
+            self.nat = Some(packet);
+            self.nat_received.push(packet);
+            return;
+        }
+        match self.queues.get_mut(packet.dest as usize) {
+            Some(queue) if packet.dest >= 0 => {
+                queue.push_back(packet.x);
+                queue.push_back(packet.y);
+            }
+            _ => self.dropped.push(packet),
+        }
+    }
+}
+
+// A machine's network interface.
+pub struct Nic {
+    address: usize,
+    router: Rc<RefCell<Router>>,
+    outgoing: Vec<isize>,
+    // Whether the machine has been handed a -1 this round. After that it
+    // gets parked until the next round instead of spinning on empty reads.
+    polled: bool,
+    // Whether the machine sent or received anything this round.
+    busy: bool,
+}
+
+impl IoDevice for Nic {
+    fn read(&mut self) -> Result<isize, WouldBlock> {
+        if let Some(value) = self.router.borrow_mut().queues[self.address].pop_front() {
+            self.busy = true;
+            return Ok(value);
+        }
+        if self.polled {
+            return Err(WouldBlock);
+        }
+        self.polled = true;
+        Ok(-1)
+    }
+
+    fn write(&mut self, value: isize) {
+        self.busy = true;
+        self.outgoing.push(value);
+        if let [dest, x, y] = self.outgoing[..] {
+            self.router.borrow_mut().route(Packet { dest, x, y });
+            self.outgoing.clear();
+        }
+    }
+}
+
+pub struct Network {
+    scheduler: Scheduler<Nic>,
+    router: Rc<RefCell<Router>>,
+}
+
+impl Network {
+    // Starts `size` copies of `machine`, with addresses 0 to size - 1.
+    pub fn boot(machine: &Machine, size: usize) -> Self {
+        let router = Rc::new(RefCell::new(Router::default()));
+        let mut scheduler = Scheduler::new();
+        for address in 0..size {
+            router
+                .borrow_mut()
+                .queues
+                .push(Some(address as isize).into_iter().collect());
+            let nic = Nic {
+                address,
+                router: router.clone(),
+                outgoing: Vec::new(),
+                polled: false,
+                busy: false,
+            };
+            scheduler.spawn(machine.clone(), nic);
+        }
+        Network { scheduler, router }
+    }
+
+    fn is_idle(&self) -> bool {
+        let router = self.router.borrow();
+        router.queues.iter().all(|queue| queue.is_empty())
+            && (0..self.scheduler.len()).all(|task| {
+                let nic = self.scheduler.io(task);
+                self.scheduler.halted(task) || (nic.polled && !nic.busy)
+            })
+    }
+
+    // Lets every machine run until it's polled its empty queue or used up its
+    // time slice, then has the NAT step in if everything's gone quiet.
+    // Returns false once nothing else can happen.
+    pub fn run_round(&mut self) -> Result<bool, (TaskId, MachineError)> {
+        for task in 0..self.scheduler.len() {
+            let nic = self.scheduler.io_mut(task);
+            nic.polled = false;
+            nic.busy = false;
+        }
+        self.scheduler.run_round()?;
+        if (0..self.scheduler.len()).all(|task| self.scheduler.halted(task)) {
+            return Ok(false);
+        }
+        if !self.is_idle() {
+            return Ok(true);
+        }
+        let mut router = self.router.borrow_mut();
+        match router.nat {
+            Some(packet) => {
+                let wake = Packet { dest: 0, ..packet };
+                router.nat_sent.push(wake);
+                router.route(wake);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Packets the NAT has received, oldest first.
+    pub fn nat_received(&self) -> Vec<Packet> {
+        self.router.borrow().nat_received.clone()
+    }
+
+    // Packets the NAT has sent to wake up address 0, oldest first.
+    pub fn nat_sent(&self) -> Vec<Packet> {
+        self.router.borrow().nat_sent.clone()
+    }
+
+    // Packets sent to addresses that don't exist.
+    pub fn dropped(&self) -> Vec<Packet> {
+        self.router.borrow().dropped.clone()
+    }
+
+    // How many packets have been sent in total, including to the NAT.
+    pub fn routed(&self) -> usize {
+        self.router.borrow().routed
+    }
+}
+
+#[cfg(test)]
+static RELAY: &str = "
+        in [addr]
+        jt [addr], #loop
+        ; Address 0 gets the ball rolling.
+        out #1
+        out #0
+        out #0
+    loop:
+        in [x]
+        eq [x], #-1 -> [t]
+        jt [t], #loop
+        in [y]
+        add [y], #1 -> [y]
+        lt [y], #10 -> [t]
+        jf [t], #nat
+        ; Pass it on to the next machine round the ring.
+        add [addr], #1 -> [dest]
+        eq [dest], #3 -> [t]
+        jf [t], #send
+        add #0, #0 -> [dest]
+    send:
+        out [dest]
+        out [x]
+        out [y]
+        jt #1, #loop
+    nat:
+        out #255
+        out [x]
+        out [y]
+        jt #1, #loop
+    addr: .data 0
+    x: .data 0
+    y: .data 0
+    t: .data 0
+    dest: .data 0
+";
+
+#[test]
+fn network_relays_and_wakes() {
+    let program = super::asm::assemble(RELAY).unwrap();
+    let machine = Machine::from_mem_spec(&program).unwrap();
+    let mut network = Network::boot(&machine, 3);
+    while network.nat_sent().len() < 3 {
+        assert_eq!(network.run_round(), Ok(true));
+    }
+    assert_eq!(
+        network.nat_received()[0],
+        Packet {
+            dest: 255,
+            x: 0,
+            y: 10
+        }
+    );
+    let woken: Vec<isize> = network.nat_sent().iter().map(|p| p.y).collect();
+    assert_eq!(woken, [10, 11, 12]);
+    assert!(network.nat_sent().iter().all(|p| p.dest == 0));
+    assert!(network.dropped().is_empty());
+    // The relay around the ring, the wake-ups, and the replies to them.
+    assert_eq!(network.routed(), 11 + 3 + 2);
+}
+
+#[test]
+fn network_gives_up_when_stuck() {
+    // Nobody ever sends anything.
+    let machine = Machine::from_mem_spec("3,100,1105,1,0").unwrap();
+    let mut network = Network::boot(&machine, 4);
+    // Reading the addresses counts as activity.
+    assert_eq!(network.run_round(), Ok(true));
+    assert_eq!(network.run_round(), Ok(false));
+}
@@ -0,0 +1,156 @@
+// Counting where a program spends its time.
+use super::disasm::Instruction;
+use super::{Machine, Opcode};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+// Filled in by `Machine::step` whenever `Machine::profile` is set. Times
+// include the profiler's own overhead, so they're only good for comparing
+// against each other.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // Executions per pc, which can be anywhere in memory.
+    hits: BTreeMap<usize, u64>,
+    // Executions and time spent per opcode.
+    opcodes: Vec<(u64, Duration)>,
+    // Times the machine asked for input that wasn't there yet.
+    pub blocked: u64,
+}
+
+impl Profile {
+    pub(super) fn record(&mut self, pc: usize, opcode: Opcode, elapsed: Duration) {
+        *self.hits.entry(pc).or_insert(0) += 1;
+        if opcode.0 >= self.opcodes.len() {
+            self.opcodes.resize(opcode.0 + 1, (0, Duration::default()));
+        }
+        let (count, time) = &mut self.opcodes[opcode.0];
+        *count += 1;
+        *time += elapsed;
+    }
+
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.hits.values().sum()
+    }
+
+    pub fn opcode(&self, opcode: Opcode) -> (u64, Duration) {
+        self.opcodes.get(opcode.0).copied().unwrap_or_default()
+    }
+
+    pub fn inputs(&self) -> u64 {
+        self.opcode(Opcode(3)).0
+    }
+
+    pub fn outputs(&self) -> u64 {
+        self.opcode(Opcode(4)).0
+    }
+
+    // The `n` most executed addresses, most executed first.
+    pub fn hot_spots(&self, n: usize) -> Vec<(usize, u64)> {
+        let mut spots: Vec<(usize, u64)> =
+            self.hits.iter().map(|(&pc, &hits)| (pc, hits)).collect();
+        spots.sort_by_key(|&(pc, hits)| (std::cmp::Reverse(hits), pc));
+        spots.truncate(n);
+        spots
+    }
+
+    // A summary with the `n` hottest instructions, disassembled from
+    // `machine`'s memory.
+    pub fn report(&self, machine: &Machine, n: usize) -> String {
+        let executed = self.executed();
+        let percent = |count: u64| 100.0 * count as f64 / executed.max(1) as f64;
+        let mut report = String::new();
+        writeln!(
+            report,
+            "{} instructions, {} inputs, {} outputs, {} blocked reads",
+            executed,
+            self.inputs(),
+            self.outputs(),
+            self.blocked
+        )
+        .unwrap();
+        writeln!(report, "\nop       count      %        time").unwrap();
+        for (opcode, &(count, time)) in self.opcodes.iter().enumerate() {
+            let info = machine.instructions().info(Opcode(opcode));
+            if let (Some((mnemonic, ..)), true) = (info, count > 0) {
+                writeln!(
+                    report,
+                    "{:<4} {:>9} {:>6.2} {:>11.3?}",
+                    mnemonic,
+                    count,
+                    percent(count),
+                    time
+                )
+                .unwrap();
+            }
+        }
+        writeln!(report, "\n    count      %  instruction").unwrap();
+        for (pc, hits) in self.hot_spots(n) {
+            let instruction = Instruction::decode_at(&machine.memory, pc, machine.instructions())
+                .map_or_else(|| "???".to_string(), |instruction| instruction.to_string());
+            writeln!(
+                report,
+                "{:>9} {:>6.2}  {:04}: {}",
+                hits,
+                percent(hits),
+                pc,
+                instruction
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+#[test]
+fn profile_counts() {
+    use super::asm::assemble;
+
+    let program = assemble(
+        "
+        loop:
+            in -> [n]
+            out [n]
+            jt [n], #loop
+            hlt
+        n:  .data 0
+        ",
+    )
+    .unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    machine.profile = Some(Profile::default());
+    machine.input.extend(vec![3, 2, 1]);
+    machine.run().unwrap_err();
+    machine.input.push_back(0);
+    machine.run().unwrap();
+
+    let profile = machine.profile.as_ref().unwrap();
+    assert_eq!(profile.executed(), 13);
+    assert_eq!(profile.inputs(), 4);
+    assert_eq!(profile.outputs(), 4);
+    assert_eq!(profile.blocked, 1);
+    assert_eq!(profile.hits(4), 4);
+    assert_eq!(profile.hits(7), 1);
+    assert_eq!(profile.opcode(Opcode(5)).0, 4);
+    assert_eq!(profile.hot_spots(2), vec![(0, 4), (2, 4)]);
+    let report = profile.report(&machine, 2);
+    assert!(report.starts_with("13 instructions, 4 inputs, 4 outputs, 1 blocked reads\n"));
+    assert!(report.contains("        4  30.77  0000: IN -> [8]\n"));
+}
+
+#[test]
+fn profile_far_jumps() {
+    // Jumps a long way to halt.
+    let mut machine = Machine::from_mem_spec("1105,1,1000000").unwrap();
+    machine.memory.resize(1_000_001);
+    machine.memory[1_000_000] = 99;
+    machine.profile = Some(Profile::default());
+    machine.run().unwrap();
+    let profile = machine.profile.unwrap();
+    assert_eq!(profile.hot_spots(5), vec![(0, 1), (1_000_000, 1)]);
+    assert_eq!(profile.hits.len(), 2);
+}
@@ -0,0 +1,213 @@
+// Runs a bunch of machines side by side, each one parked whenever it's
+// waiting for input. Everything is single-threaded and deterministic: the
+// scheduler just goes round the tasks in order, giving each a time slice.
+use super::io::{IoDevice, WouldBlock};
+use super::{Machine, MachineError, Step};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// A queue of values between tasks. Clones share the same queue.
+#[derive(Debug, Clone, Default)]
+pub struct Channel(Rc<RefCell<VecDeque<isize>>>);
+
+impl Channel {
+    pub fn new() -> Self {
+        Channel::default()
+    }
+
+    pub fn send(&self, value: isize) {
+        self.0.borrow_mut().push_back(value);
+    }
+
+    pub fn recv(&self) -> Option<isize> {
+        self.0.borrow_mut().pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+// The usual way to hook a task up: one channel in, one channel out.
+#[derive(Debug, Clone)]
+pub struct Ports {
+    pub input: Channel,
+    pub output: Channel,
+}
+
+impl IoDevice for Ports {
+    fn read(&mut self) -> Result<isize, WouldBlock> {
+        self.input.recv().ok_or(WouldBlock)
+    }
+
+    fn write(&mut self, value: isize) {
+        self.output.send(value);
+    }
+}
+
+pub type TaskId = usize;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Outcome {
+    // Every task has halted.
+    Halted,
+    // Nothing can make progress: every task left is waiting for input that
+    // isn't coming.
+    Idle,
+}
+
+struct Task<D> {
+    machine: Machine,
+    io: D,
+    halted: bool,
+}
+
+// How many instructions a task gets before we move on to the next one.
+// This only matters for tasks that spin without ever blocking.
+const TIME_SLICE: usize = 1000;
+
+pub struct Scheduler<D = Ports> {
+    tasks: Vec<Task<D>>,
+}
+
+impl<D: IoDevice> Scheduler<D> {
+    pub fn new() -> Self {
+        Scheduler { tasks: Vec::new() }
+    }
+
+    pub fn spawn(&mut self, machine: Machine, io: D) -> TaskId {
+        self.tasks.push(Task {
+            machine,
+            io,
+            halted: false,
+        });
+        self.tasks.len() - 1
+    }
+
+    pub fn machine(&self, task: TaskId) -> &Machine {
+        &self.tasks[task].machine
+    }
+
+    pub fn io(&self, task: TaskId) -> &D {
+        &self.tasks[task].io
+    }
+
+    pub fn io_mut(&mut self, task: TaskId) -> &mut D {
+        &mut self.tasks[task].io
+    }
+
+    pub fn halted(&self, task: TaskId) -> bool {
+        self.tasks[task].halted
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // Gives every live task one time slice. Returns whether any of them
+    // managed to execute anything.
+    pub fn run_round(&mut self) -> Result<bool, (TaskId, MachineError)> {
+        let mut progress = false;
+        for (id, task) in self.tasks.iter_mut().enumerate() {
+            for _ in 0..TIME_SLICE {
+                if task.halted {
+                    break;
+                }
+                match task.machine.step_with(&mut task.io) {
+                    Ok(Step::Input) => break,
+                    Ok(Step::Halt) => task.halted = true,
+                    Ok(_) => progress = true,
+                    Err(err) => return Err((id, err)),
+                }
+            }
+        }
+        Ok(progress)
+    }
+
+    // Runs until every task halts or they're all stuck waiting for input.
+    pub fn run(&mut self) -> Result<Outcome, (TaskId, MachineError)> {
+        loop {
+            let progress = self.run_round()?;
+            if self.tasks.iter().all(|task| task.halted) {
+                return Ok(Outcome::Halted);
+            }
+            if !progress {
+                return Ok(Outcome::Idle);
+            }
+        }
+    }
+}
+
+impl<D: IoDevice> Default for Scheduler<D> {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+#[test]
+fn scheduler_pipeline() {
+    use super::asm::assemble;
+
+    // Adds one to everything that comes through.
+    let increment = assemble(
+        "
+        loop:
+            in [x]
+            add [x], #1 -> [x]
+            out [x]
+            jt #1, #loop
+        x:  .data 0
+        ",
+    )
+    .unwrap();
+    let machine = Machine::from_mem_spec(&increment).unwrap();
+
+    let mut scheduler = Scheduler::new();
+    assert!(scheduler.is_empty());
+    let first = Channel::new();
+    let mut input = first.clone();
+    for _ in 0..3 {
+        let output = Channel::new();
+        scheduler.spawn(
+            machine.clone(),
+            Ports {
+                input,
+                output: output.clone(),
+            },
+        );
+        input = output;
+    }
+    first.send(10);
+    first.send(20);
+    assert_eq!(scheduler.run(), Ok(Outcome::Idle));
+    assert_eq!(input.recv(), Some(13));
+    assert_eq!(input.recv(), Some(23));
+    assert!(input.is_empty());
+    // Each one is waiting for more, holding the last thing it sent on.
+    assert_eq!(scheduler.len(), 3);
+    let x = 11;
+    let held: Vec<_> = (0..3)
+        .map(|task| scheduler.machine(task).memory[x])
+        .collect();
+    assert_eq!(held, [21, 22, 23]);
+}
+
+#[test]
+fn scheduler_reports_faults() {
+    let mut scheduler = Scheduler::new();
+    let ports = Ports {
+        input: Channel::new(),
+        output: Channel::new(),
+    };
+    scheduler.spawn(Machine::from_mem_spec("99").unwrap(), ports.clone());
+    scheduler.spawn(Machine::from_mem_spec("1,0,0,0,42").unwrap(), ports);
+    assert_eq!(
+        scheduler.run(),
+        Err((1, MachineError::UnknownOpcode { pc: 4, code: 42 }))
+    );
+}
@@ -0,0 +1,141 @@
+// Spotting programs that write over their own instructions, which is worth
+// knowing before trusting anything that caches or compiles them.
+use super::{Machine, Word};
+use std::collections::HashMap;
+use std::fmt;
+
+// A write to an instruction that had already been executed. If `addr` is
+// `instruction` then `old` and `new` are opcodes, otherwise it was an operand
+// that got changed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Patch<W = isize> {
+    pub pc: usize,
+    pub addr: usize,
+    pub instruction: usize,
+    pub old: W,
+    pub new: W,
+}
+
+// Prints as e.g. `0006: patched [225] 1->5, the opcode at 0225`.
+impl<W: fmt::Display> fmt::Display for Patch<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}: patched [{}] {}->{}, ",
+            self.pc, self.addr, self.old, self.new
+        )?;
+        if self.addr == self.instruction {
+            write!(f, "the opcode at {:04}", self.instruction)
+        } else {
+            write!(f, "an operand of {:04}", self.instruction)
+        }
+    }
+}
+
+// Filled in by the machine whenever `Machine::self_modification` is set.
+#[derive(Debug, Clone)]
+pub struct SelfModification<W = isize> {
+    // Every address that's been part of an executed instruction, and which
+    // instruction that was. The latest one wins if instructions overlap.
+    executed: HashMap<usize, usize>,
+    pub patches: Vec<Patch<W>>,
+}
+
+impl<W> Default for SelfModification<W> {
+    fn default() -> Self {
+        SelfModification {
+            executed: HashMap::new(),
+            patches: Vec::new(),
+        }
+    }
+}
+
+impl<W: Clone> SelfModification<W> {
+    pub fn is_self_modifying(&self) -> bool {
+        !self.patches.is_empty()
+    }
+
+    pub fn executed(&self, addr: usize) -> bool {
+        self.executed.contains_key(&addr)
+    }
+
+    // Only once the instruction has actually run, so one that faults or
+    // waits for input doesn't count.
+    pub(super) fn ran(&mut self, pc: usize, len: usize) {
+        for addr in pc..pc + len {
+            self.executed.insert(addr, pc);
+        }
+    }
+
+    pub(super) fn record(&mut self, pc: usize, addr: usize, old: &W, new: &W) {
+        if let Some(&instruction) = self.executed.get(&addr) {
+            self.patches.push(Patch {
+                pc,
+                addr,
+                instruction,
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+    }
+}
+
+impl<W: Word> Machine<W> {
+    pub fn detect_self_modification(&mut self) {
+        self.self_modification
+            .get_or_insert_with(SelfModification::default);
+    }
+}
+
+#[test]
+fn patches_are_reported() {
+    use super::asm::assemble;
+
+    // Counts down by rewriting the `out` instruction's operand each time.
+    let program = assemble(
+        "
+        loop:
+            out #3
+            add [1], #-1 -> [1]
+            jt [1], #loop
+            hlt
+        ",
+    )
+    .unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    machine.detect_self_modification();
+    machine.run().unwrap();
+    assert_eq!(machine.output, vec![3, 2, 1]);
+    let patches = machine.self_modification.unwrap().patches;
+    let patches: Vec<String> = patches.iter().map(|patch| patch.to_string()).collect();
+    assert_eq!(
+        patches,
+        vec![
+            "0002: patched [1] 3->2, an operand of 0000",
+            "0002: patched [1] 2->1, an operand of 0000",
+            "0002: patched [1] 1->0, an operand of 0000",
+        ]
+    );
+
+    // Writing to data that's never run doesn't count.
+    let mut machine = Machine::from_mem_spec("1101,1,2,5,99,0").unwrap();
+    machine.detect_self_modification();
+    machine.run().unwrap();
+    assert!(!machine.self_modification.unwrap().is_self_modifying());
+}
+
+#[test]
+fn blocked_instructions_are_not_executed() {
+    let mut machine = Machine::from_mem_spec("3,5,99").unwrap();
+    machine.detect_self_modification();
+    assert_eq!(machine.step(), Ok(super::Step::Input));
+    assert!(!machine.self_modification.as_ref().unwrap().executed(0));
+
+    machine.input.push_back(7);
+    machine.run().unwrap();
+    let self_modification = machine.self_modification.unwrap();
+    assert!(self_modification.executed(0));
+    assert!(self_modification.executed(1));
+    assert!(self_modification.executed(2));
+    assert!(!self_modification.executed(5));
+}
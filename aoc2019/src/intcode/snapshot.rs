@@ -0,0 +1,291 @@
+// Saving and loading machines, so long runs can be checkpointed and
+// failing states attached to bug reports.
+//
+// The format is little-endian throughout:
+//
+//     magic    "ICSNAP"
+//     version  u8, currently 4
+//     pc       u64
+//     rb       i64
+//     arith    u8, 0 for wrapping or 1 for checked
+//     limit    u64 memory limit
+//     isa      u8 length, then the instruction set's name as UTF-8, see
+//              `InstructionSet::named`
+//     memory   u64 length, u64 page count, then for each page its u64 page
+//              number and `PAGE_SIZE` i64s
+//     input    u64 length, then that many i64s
+//     output   u64 length, then that many i64s
+//
+// Pages that are all zero are left out, since memory is sparse and a
+// program that touches a high address would otherwise make a huge file.
+//
+// Traces and anything else that's purely bookkeeping aren't saved. Nor are
+// custom instructions, since there's no way to write out a function, so
+// machines with them can't be saved at all.
+use super::isa::InstructionSet;
+use super::memory::{Memory, PAGE_SIZE};
+use super::{Arithmetic, Machine};
+use derive_more::{Display, From};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 6] = b"ICSNAP";
+const VERSION: u8 = 4;
+
+#[derive(Debug, Display, From)]
+pub enum SnapshotError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+    #[display(fmt = "not a machine snapshot")]
+    BadMagic,
+    #[display(fmt = "unsupported snapshot version {}", _0)]
+    #[from(ignore)]
+    UnsupportedVersion(u8),
+    #[display(fmt = "unknown arithmetic mode {}", _0)]
+    #[from(ignore)]
+    BadArithmetic(u8),
+    #[display(fmt = "page {} is past the end of memory", _0)]
+    #[from(ignore)]
+    BadPage(usize),
+    #[display(fmt = "custom instructions can't be saved")]
+    CustomInstructions,
+    #[display(fmt = "unknown instruction set {:?}", _0)]
+    #[from(ignore)]
+    UnknownInstructions(String),
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn write_words(
+    w: &mut impl Write,
+    len: usize,
+    words: impl Iterator<Item = isize>,
+) -> io::Result<()> {
+    w.write_all(&(len as u64).to_le_bytes())?;
+    for word in words {
+        w.write_all(&(word as i64).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_words(r: &mut impl Read) -> io::Result<Vec<isize>> {
+    let len = read_u64(r)?;
+    (0..len).map(|_| Ok(read_u64(r)? as i64 as isize)).collect()
+}
+
+fn write_memory(w: &mut impl Write, memory: &Memory) -> io::Result<()> {
+    let pages: Vec<_> = (memory.pages())
+        .filter(|(_, contents)| contents.iter().any(|&word| word != 0))
+        .collect();
+    w.write_all(&(memory.len() as u64).to_le_bytes())?;
+    w.write_all(&(pages.len() as u64).to_le_bytes())?;
+    for (page, contents) in pages {
+        w.write_all(&(page as u64).to_le_bytes())?;
+        for &word in contents.iter() {
+            w.write_all(&(word as i64).to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_memory(r: &mut impl Read) -> Result<Memory, SnapshotError> {
+    let mut memory = Memory::new();
+    memory.resize(read_u64(r)? as usize);
+    let pages = read_u64(r)?;
+    for _ in 0..pages {
+        let page = read_u64(r)? as usize;
+        let start = page.saturating_mul(PAGE_SIZE);
+        if start >= memory.len() {
+            return Err(SnapshotError::BadPage(page));
+        }
+        // Anything past the end of memory has to stay zero.
+        let end = memory.len().min(start + PAGE_SIZE);
+        for addr in start..start + PAGE_SIZE {
+            let word = read_u64(r)? as i64 as isize;
+            if addr < end {
+                memory[addr] = word;
+            }
+        }
+    }
+    Ok(memory)
+}
+
+impl Machine {
+    pub fn save_snapshot(&self, mut w: impl Write) -> Result<(), SnapshotError> {
+        // Checked first, so nothing gets written.
+        let isa = (self.instructions().name()).ok_or(SnapshotError::CustomInstructions)?;
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        w.write_all(&(self.pc as u64).to_le_bytes())?;
+        w.write_all(&(self.relative_base as i64).to_le_bytes())?;
+        let arithmetic = match self.arithmetic {
+            Arithmetic::Wrapping => 0,
+            Arithmetic::Checked => 1,
+        };
+        w.write_all(&[arithmetic])?;
+        w.write_all(&(self.memory_limit as u64).to_le_bytes())?;
+        w.write_all(&[isa.len() as u8])?;
+        w.write_all(isa.as_bytes())?;
+        write_memory(&mut w, &self.memory)?;
+        write_words(&mut w, self.input.len(), self.input.iter().copied())?;
+        write_words(&mut w, self.output.len(), self.output.iter().copied())?;
+        Ok(())
+    }
+
+    pub fn load_snapshot(mut r: impl Read) -> Result<Self, SnapshotError> {
+        let mut magic = [0; 6];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut version = [0];
+        r.read_exact(&mut version)?;
+        if version[0] != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version[0]));
+        }
+        let pc = read_u64(&mut r)? as usize;
+        let relative_base = read_u64(&mut r)? as i64 as isize;
+        let mut arithmetic = [0];
+        r.read_exact(&mut arithmetic)?;
+        let arithmetic = match arithmetic[0] {
+            0 => Arithmetic::Wrapping,
+            1 => Arithmetic::Checked,
+            other => return Err(SnapshotError::BadArithmetic(other)),
+        };
+        let memory_limit = read_u64(&mut r)? as usize;
+        let mut len = [0];
+        r.read_exact(&mut len)?;
+        let mut name = vec![0; len[0] as usize];
+        r.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name);
+        let instructions = InstructionSet::named(&name)
+            .ok_or_else(|| SnapshotError::UnknownInstructions(name.into_owned()))?;
+        let memory = read_memory(&mut r)?;
+        let input = read_words(&mut r)?.into_iter().collect();
+        let output = read_words(&mut r)?;
+        let mut machine = Machine::from_words(Vec::new());
+        machine.memory = memory;
+        machine.set_instructions(instructions);
+        machine.pc = pc;
+        machine.relative_base = relative_base;
+        machine.arithmetic = arithmetic;
+        machine.memory_limit = memory_limit;
+        machine.input = input;
+        machine.output = output;
+        Ok(machine)
+    }
+}
+
+#[test]
+fn snapshot_resumes_exactly() {
+    let mut machine = Machine::from_mem_spec(super::BIG_TEST).unwrap();
+    machine.relative_base = -7;
+    machine.arithmetic = Arithmetic::Checked;
+    machine.memory_limit = 1000;
+    machine.output.push(42);
+    machine.input.extend(&[3, 9]);
+    machine.step().unwrap();
+    machine.step().unwrap();
+
+    let mut bytes = Vec::new();
+    machine.save_snapshot(&mut bytes).unwrap();
+    let mut restored = Machine::load_snapshot(&bytes[..]).unwrap();
+    assert_eq!(restored.pc, 6);
+    assert_eq!(restored.relative_base, -7);
+    assert_eq!(restored.arithmetic, Arithmetic::Checked);
+    assert_eq!(restored.memory_limit, 1000);
+    assert_eq!(restored.memory, machine.memory);
+    assert_eq!(restored.input, [9]);
+
+    machine.run().unwrap();
+    restored.run().unwrap();
+    assert_eq!(restored.output, machine.output);
+    assert_eq!(&restored.output, &[42, 999]);
+}
+
+#[test]
+fn snapshot_rejects_garbage() {
+    let bad_magic = Machine::load_snapshot(&b"NOTASNAPSHOT"[..]);
+    assert!(matches!(bad_magic, Err(SnapshotError::BadMagic)));
+    let bad_version = Machine::load_snapshot(&b"ICSNAP\x07"[..]);
+    assert!(matches!(
+        bad_version,
+        Err(SnapshotError::UnsupportedVersion(7))
+    ));
+    let truncated = Machine::load_snapshot(&b"ICSNAP\x04\x00"[..]);
+    assert!(matches!(truncated, Err(SnapshotError::Io(_))));
+    // No memory, but one page of it anyway.
+    let mut bad_page = b"ICSNAP\x04".to_vec();
+    bad_page.extend(&[0; 16]);
+    bad_page.push(0);
+    bad_page.extend(&1000u64.to_le_bytes());
+    bad_page.extend(b"\x04day2");
+    for field in &[0u64, 1, 5] {
+        bad_page.extend(&field.to_le_bytes());
+    }
+    let bad_page = Machine::load_snapshot(&bad_page[..]);
+    assert!(matches!(bad_page, Err(SnapshotError::BadPage(5))));
+}
+
+#[test]
+fn snapshots_stay_sparse() {
+    let mut machine = Machine::from_mem_spec("1101,1,2,100000000,99").unwrap();
+    machine.run().unwrap();
+    let mut bytes = Vec::new();
+    machine.save_snapshot(&mut bytes).unwrap();
+    // Two pages, not a hundred million words.
+    assert!(bytes.len() < 3 * PAGE_SIZE * 8, "{} bytes", bytes.len());
+    let restored = Machine::load_snapshot(&bytes[..]).unwrap();
+    assert_eq!(restored.memory.len(), 100_000_001);
+    assert_eq!(restored.memory[100_000_000], 3);
+    assert_eq!(restored.memory[0], 1101);
+    assert_eq!(restored.memory.allocated_pages(), 2);
+}
+
+#[test]
+fn snapshot_rejects_unknown_arithmetic() {
+    let mut bytes = b"ICSNAP\x04".to_vec();
+    bytes.extend(&[0; 16]);
+    bytes.push(2);
+    let restored = Machine::load_snapshot(&bytes[..]);
+    assert!(matches!(restored, Err(SnapshotError::BadArithmetic(2))));
+}
+
+#[test]
+fn snapshots_keep_the_instruction_set() {
+    // Would output 3 under anything later than day 2.
+    let mut machine = Machine::from_mem_spec("1101,1,2,7,4,7,99,0").unwrap();
+    machine.set_instructions(InstructionSet::day2());
+    machine.step().unwrap();
+    let mut bytes = Vec::new();
+    machine.save_snapshot(&mut bytes).unwrap();
+    let mut restored = Machine::load_snapshot(&bytes[..]).unwrap();
+    assert_eq!(restored.instructions().name(), Some("day2"));
+    assert_eq!(
+        restored.run(),
+        Err(super::MachineError::UnknownOpcode { pc: 4, code: 4 })
+    );
+
+    let mut unknown = bytes.clone();
+    // Skips the magic, version, pc, rb, arithmetic, limit and name length.
+    unknown[7 + 8 + 8 + 1 + 8 + 1..][..4].copy_from_slice(b"day3");
+    let unknown = Machine::load_snapshot(&unknown[..]);
+    assert!(matches!(unknown, Err(SnapshotError::UnknownInstructions(name)) if name == "day3"));
+}
+
+#[test]
+fn custom_instructions_are_not_saved() {
+    let mut instructions = InstructionSet::full();
+    instructions.register(super::Opcode(10), "NOP", 0, None, |_| Ok(0));
+    let mut machine = Machine::from_mem_spec("10,99").unwrap();
+    machine.set_instructions(instructions);
+    let mut bytes = Vec::new();
+    let saved = machine.save_snapshot(&mut bytes);
+    assert!(matches!(saved, Err(SnapshotError::CustomInstructions)));
+    assert!(bytes.is_empty());
+}
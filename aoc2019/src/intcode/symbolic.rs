@@ -0,0 +1,607 @@
+// Running programs on symbols instead of numbers, to work out what inputs
+// give a particular answer without trying them all.
+//
+// Control flow has to stay concrete: anything that would need the machine
+// to branch, jump, or write somewhere depending on a symbol is reported as
+// an error rather than explored.
+use super::memory::Paged;
+use super::{parse_opcode, ArgMode, MachineError, Opcode, DEFAULT_MEMORY_LIMIT};
+use derive_more::{Display, From};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Deref, RangeInclusive};
+use std::rc::Rc;
+
+// How deep expressions can nest. Evaluating and printing them recurses, so
+// a loop that keeps adding to the same cell has to be stopped somewhere.
+pub const MAX_DEPTH: usize = 1000;
+
+// A shared expression, which remembers how deeply it nests.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Value {
+    expr: Rc<Expr>,
+    depth: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Const(isize),
+    // Indexes the values passed to `eval` and `solve`.
+    Symbol(usize, Rc<str>),
+    Add(Value, Value),
+    Mul(Value, Value),
+    LessThan(Value, Value),
+    Equals(Value, Value),
+    // Whatever was at a symbolic address, in memory as it was then. The
+    // pages are shared until one side writes to them.
+    Load(Value, Paged<Value>),
+}
+
+impl Value {
+    // Loads don't count the memory they load from, see `Symbolic::deepest`.
+    fn new(expr: Expr) -> Self {
+        let depth = match &expr {
+            Expr::Const(_) | Expr::Symbol(..) => 0,
+            Expr::Add(a, b) | Expr::Mul(a, b) | Expr::LessThan(a, b) | Expr::Equals(a, b) => {
+                a.depth.max(b.depth)
+            }
+            Expr::Load(addr, _) => addr.depth,
+        } + 1;
+        Value {
+            expr: Rc::new(expr),
+            depth,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // Moves this value's children onto `stack`, if nobody else has them.
+    fn take_children(&mut self, stack: &mut Vec<Value>) {
+        if let Some(expr) = Rc::get_mut(&mut self.expr) {
+            match std::mem::replace(expr, Expr::Const(0)) {
+                Expr::Add(a, b) | Expr::Mul(a, b) | Expr::LessThan(a, b) | Expr::Equals(a, b) => {
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Expr::Load(addr, _) => stack.push(addr),
+                Expr::Const(_) | Expr::Symbol(..) => (),
+            }
+        }
+    }
+}
+
+impl Deref for Value {
+    type Target = Expr;
+
+    fn deref(&self) -> &Expr {
+        &self.expr
+    }
+}
+
+// Dropping a long chain one link at a time, rather than recursively.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(mut value) = stack.pop() {
+            value.take_children(&mut stack);
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        constant(0)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
+
+fn constant(value: isize) -> Value {
+    Value::new(Expr::Const(value))
+}
+
+// These fold constants as they go, so that anything that doesn't depend on
+// a symbol stays a plain number.
+fn add(a: Value, b: Value) -> Value {
+    match (&*a, &*b) {
+        (&Expr::Const(a), &Expr::Const(b)) => constant(a.wrapping_add(b)),
+        (Expr::Const(0), _) => b,
+        (_, Expr::Const(0)) => a,
+        _ => Value::new(Expr::Add(a, b)),
+    }
+}
+
+fn mul(a: Value, b: Value) -> Value {
+    match (&*a, &*b) {
+        (&Expr::Const(a), &Expr::Const(b)) => constant(a.wrapping_mul(b)),
+        (Expr::Const(0), _) | (_, Expr::Const(1)) => a,
+        (_, Expr::Const(0)) | (Expr::Const(1), _) => b,
+        _ => Value::new(Expr::Mul(a, b)),
+    }
+}
+
+fn less_than(a: Value, b: Value) -> Value {
+    match (&*a, &*b) {
+        (Expr::Const(a), Expr::Const(b)) => constant((a < b) as isize),
+        _ => Value::new(Expr::LessThan(a, b)),
+    }
+}
+
+fn equals(a: Value, b: Value) -> Value {
+    match (&*a, &*b) {
+        (Expr::Const(a), Expr::Const(b)) => constant((a == b) as isize),
+        _ => Value::new(Expr::Equals(a, b)),
+    }
+}
+
+impl Expr {
+    pub fn as_const(&self) -> Option<isize> {
+        match *self {
+            Expr::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // `None` if it loads from a negative address.
+    pub fn eval(&self, values: &[isize]) -> Option<isize> {
+        Some(match self {
+            Expr::Const(value) => *value,
+            Expr::Symbol(i, _) => values[*i],
+            Expr::Add(a, b) => a.eval(values)?.wrapping_add(b.eval(values)?),
+            Expr::Mul(a, b) => a.eval(values)?.wrapping_mul(b.eval(values)?),
+            Expr::LessThan(a, b) => (a.eval(values)? < b.eval(values)?) as isize,
+            Expr::Equals(a, b) => (a.eval(values)? == b.eval(values)?) as isize,
+            Expr::Load(addr, memory) => {
+                let addr = addr.eval(values)?;
+                if addr < 0 {
+                    return None;
+                }
+                match memory.get(addr as usize) {
+                    Some(value) => value.eval(values)?,
+                    None => 0,
+                }
+            }
+        })
+    }
+
+    // The expression as `constant + coefficients · symbols`, if it's that
+    // simple.
+    fn linear(&self, symbols: usize) -> Option<(isize, Vec<isize>)> {
+        Some(match self {
+            Expr::Const(value) => (*value, vec![0; symbols]),
+            Expr::Symbol(i, _) => {
+                let mut coefficients = vec![0; symbols];
+                coefficients[*i] = 1;
+                (0, coefficients)
+            }
+            Expr::Add(a, b) => {
+                let (a, mut coefficients) = a.linear(symbols)?;
+                let (b, others) = b.linear(symbols)?;
+                for (c, other) in coefficients.iter_mut().zip(others) {
+                    *c = c.wrapping_add(other);
+                }
+                (a.wrapping_add(b), coefficients)
+            }
+            Expr::Mul(a, b) => {
+                let (scale, expr) = match (a.as_const(), b.as_const()) {
+                    (Some(scale), _) => (scale, b),
+                    (_, Some(scale)) => (scale, a),
+                    _ => return None,
+                };
+                let (value, coefficients) = expr.linear(symbols)?;
+                let coefficients = coefficients.iter().map(|c| c.wrapping_mul(scale));
+                (value.wrapping_mul(scale), coefficients.collect())
+            }
+            _ => return None,
+        })
+    }
+}
+
+// Prints as e.g. `((noun * 3) + [verb]) < 7`.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn operand(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+            match **value {
+                Expr::Const(_) | Expr::Symbol(..) | Expr::Load(..) => write!(f, "{}", value),
+                _ => write!(f, "({})", value),
+            }
+        }
+        let (a, op, b) = match self {
+            Expr::Const(value) => return write!(f, "{}", value),
+            Expr::Symbol(_, name) => return write!(f, "{}", name),
+            Expr::Load(addr, _) => return write!(f, "[{}]", addr),
+            Expr::Add(a, b) => (a, "+", b),
+            Expr::Mul(a, b) => (a, "*", b),
+            Expr::LessThan(a, b) => (a, "<", b),
+            Expr::Equals(a, b) => (a, "==", b),
+        };
+        operand(f, a)?;
+        write!(f, " {} ", op)?;
+        operand(f, b)
+    }
+}
+
+#[derive(Debug, Display, PartialEq, Eq, Clone, From)]
+pub enum SymbolicError {
+    #[display(fmt = "{}", _0)]
+    Machine(MachineError),
+    #[display(fmt = "opcode depends on symbols at pc {}", pc)]
+    #[from(ignore)]
+    Opcode { pc: usize },
+    #[display(fmt = "jump depends on symbols at pc {}", pc)]
+    #[from(ignore)]
+    Jump { pc: usize },
+    #[display(fmt = "write address depends on symbols at pc {}", pc)]
+    #[from(ignore)]
+    Write { pc: usize },
+    #[display(fmt = "relative base depends on symbols at pc {}", pc)]
+    #[from(ignore)]
+    RelativeBase { pc: usize },
+    #[display(fmt = "expression nests more than {} deep at pc {}", MAX_DEPTH, pc)]
+    #[from(ignore)]
+    TooDeep { pc: usize },
+}
+
+impl std::error::Error for SymbolicError {}
+
+// Like `Machine`, but with a value in every memory cell.
+#[derive(Debug, Clone)]
+pub struct Symbolic {
+    pub pc: usize,
+    pub relative_base: isize,
+    pub memory: Paged<Value>,
+    pub input: VecDeque<Value>,
+    pub output: Vec<Value>,
+    symbols: Vec<Rc<str>>,
+    // The deepest value ever stored. Evaluating a load can go that deep
+    // into whatever it loads, so loads count it as part of their depth.
+    deepest: usize,
+}
+
+impl Symbolic {
+    pub fn new(memory: &[isize]) -> Self {
+        Symbolic {
+            pc: 0,
+            relative_base: 0,
+            memory: Paged::from(
+                memory
+                    .iter()
+                    .map(|&word| constant(word))
+                    .collect::<Vec<_>>(),
+            ),
+            input: VecDeque::new(),
+            output: Vec::new(),
+            symbols: Vec::new(),
+            deepest: 1,
+        }
+    }
+
+    pub fn symbol(&mut self, name: &str) -> Value {
+        self.symbols.push(name.into());
+        Value::new(Expr::Symbol(self.symbols.len() - 1, name.into()))
+    }
+
+    // Replaces whatever's at `addr` with a new symbol.
+    pub fn symbol_at(&mut self, addr: usize, name: &str) -> Result<Value, MachineError> {
+        self.grow_mem(addr)?;
+        let symbol = self.symbol(name);
+        self.memory[addr] = symbol.clone();
+        Ok(symbol)
+    }
+
+    // Queues up a new symbol as input.
+    pub fn symbol_input(&mut self, name: &str) -> Value {
+        let symbol = self.symbol(name);
+        self.input.push_back(symbol.clone());
+        symbol
+    }
+
+    fn grow_mem(&mut self, addr: usize) -> Result<(), MachineError> {
+        if addr >= DEFAULT_MEMORY_LIMIT {
+            return Err(MachineError::MemoryLimit {
+                pc: self.pc,
+                addr,
+                limit: DEFAULT_MEMORY_LIMIT,
+            });
+        }
+        if addr >= self.memory.len() {
+            self.memory.resize(addr + 1);
+        }
+        Ok(())
+    }
+
+    fn address(&self, addr: isize) -> Result<usize, MachineError> {
+        if addr < 0 {
+            Err(MachineError::NegativeAddress { pc: self.pc, addr })
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    // Where argument `i` points, or what it points at if that's symbolic.
+    fn pointer(&mut self, i: usize, mode: ArgMode) -> Result<Result<usize, Value>, SymbolicError> {
+        let addr = self.pc + 1 + i;
+        self.grow_mem(addr)?;
+        let arg = self.memory[addr].clone();
+        let arg = match mode {
+            ArgMode::Immediate => return Ok(Ok(addr)),
+            ArgMode::Position => arg,
+            ArgMode::Relative => add(arg, constant(self.relative_base)),
+        };
+        Ok(match arg.as_const() {
+            Some(pointer) => Ok(self.address(pointer)?),
+            None => Err(arg),
+        })
+    }
+
+    fn read(&mut self, i: usize, mode: ArgMode) -> Result<Value, SymbolicError> {
+        Ok(match self.pointer(i, mode)? {
+            Ok(addr) => {
+                self.grow_mem(addr)?;
+                self.memory[addr].clone()
+            }
+            Err(addr) => {
+                let load = Value::new(Expr::Load(addr, self.memory.clone()));
+                let depth = load.depth.max(self.deepest + 1);
+                self.bounded(Value {
+                    depth,
+                    expr: load.expr.clone(),
+                })?
+            }
+        })
+    }
+
+    fn bounded(&self, value: Value) -> Result<Value, SymbolicError> {
+        if value.depth > MAX_DEPTH {
+            Err(SymbolicError::TooDeep { pc: self.pc })
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn write(&mut self, i: usize, mode: ArgMode, value: Value) -> Result<(), SymbolicError> {
+        if mode == ArgMode::Immediate {
+            return Err(MachineError::ImmediateWrite { pc: self.pc }.into());
+        }
+        let addr = (self.pointer(i, mode)?).map_err(|_| SymbolicError::Write { pc: self.pc })?;
+        self.grow_mem(addr)?;
+        self.deepest = self.deepest.max(value.depth);
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    // Runs until the program halts.
+    pub fn run(&mut self) -> Result<(), SymbolicError> {
+        loop {
+            self.grow_mem(self.pc)?;
+            let pc = self.pc;
+            let code = (self.memory[pc].as_const()).ok_or(SymbolicError::Opcode { pc })?;
+            let (opcode, modes) = parse_opcode(pc, code)?;
+            match opcode {
+                Opcode(1) | Opcode(2) | Opcode(7) | Opcode(8) => {
+                    let (a, b) = (self.read(0, modes[0])?, self.read(1, modes[1])?);
+                    let result = match opcode {
+                        Opcode(1) => add(a, b),
+                        Opcode(2) => mul(a, b),
+                        Opcode(7) => less_than(a, b),
+                        _ => equals(a, b),
+                    };
+                    let result = self.bounded(result)?;
+                    self.write(2, modes[2], result)?;
+                    self.pc += 4;
+                }
+                Opcode(3) => {
+                    let value =
+                        (self.input.pop_front()).ok_or(MachineError::MissingInput { pc })?;
+                    self.write(0, modes[0], value)?;
+                    self.pc += 2;
+                }
+                Opcode(4) => {
+                    let value = self.read(0, modes[0])?;
+                    self.output.push(value);
+                    self.pc += 2;
+                }
+                Opcode(5) | Opcode(6) => {
+                    let condition = self.read(0, modes[0])?;
+                    let condition = condition.as_const().ok_or(SymbolicError::Jump { pc })?;
+                    if (condition != 0) == (opcode == Opcode(5)) {
+                        let target = self.read(1, modes[1])?;
+                        let target = target.as_const().ok_or(SymbolicError::Jump { pc })?;
+                        self.pc = self.address(target)?;
+                    } else {
+                        self.pc += 3;
+                    }
+                }
+                Opcode(9) => {
+                    let offset = self.read(0, modes[0])?;
+                    let offset = offset
+                        .as_const()
+                        .ok_or(SymbolicError::RelativeBase { pc })?;
+                    self.relative_base = self.relative_base.wrapping_add(offset);
+                    self.pc += 2;
+                }
+                Opcode(99) => return Ok(()),
+                _ => return Err(MachineError::UnknownOpcode { pc, code }.into()),
+            }
+        }
+    }
+
+    // Finds values for every symbol, in the order they were made and each
+    // within its range, that make `expr` come out as `target`. The first
+    // one found wins, trying earlier symbols' smaller values first.
+    pub fn solve(
+        &self,
+        expr: &Expr,
+        target: isize,
+        ranges: &[RangeInclusive<isize>],
+    ) -> Option<Vec<isize>> {
+        assert_eq!(ranges.len(), self.symbols.len(), "one range per symbol");
+        let mut ranges = ranges.to_vec();
+        let mut values = Vec::new();
+        // If the last symbol that matters only gets scaled and added, there's
+        // no need to try every value of it, it can be worked out directly.
+        let linear = expr.linear(ranges.len());
+        let solved = linear
+            .as_ref()
+            .and_then(|(_, coefficients)| coefficients.iter().rposition(|&c| c != 0));
+        let found = match (linear, solved) {
+            (Some((constant, coefficients)), Some(solved)) => {
+                let range = std::mem::replace(&mut ranges[solved], 0..=0);
+                let c = coefficients[solved];
+                let mut check = |values: &mut Vec<isize>| {
+                    values[solved] = 0;
+                    let rest = (values.iter().zip(&coefficients))
+                        .fold(constant, |sum, (v, c)| sum.wrapping_add(v.wrapping_mul(*c)));
+                    let remainder = target.wrapping_sub(rest);
+                    values[solved] = remainder.wrapping_div(c);
+                    remainder.checked_rem(c) == Some(0) && range.contains(&values[solved])
+                };
+                each_assignment(&ranges, &mut values, &mut check)
+            }
+            _ => {
+                let mut check = |values: &mut Vec<isize>| expr.eval(values) == Some(target);
+                each_assignment(&ranges, &mut values, &mut check)
+            }
+        };
+        if found {
+            Some(values)
+        } else {
+            None
+        }
+    }
+}
+
+// Tries every combination of values in order, until `found` says stop.
+fn each_assignment(
+    ranges: &[RangeInclusive<isize>],
+    values: &mut Vec<isize>,
+    found: &mut impl FnMut(&mut Vec<isize>) -> bool,
+) -> bool {
+    let i = values.len();
+    if i == ranges.len() {
+        return found(values);
+    }
+    for value in ranges[i].clone() {
+        values.push(value);
+        if each_assignment(ranges, values, found) {
+            return true;
+        }
+        values.pop();
+    }
+    false
+}
+
+#[test]
+fn symbolic_expressions() {
+    // Reads two inputs and outputs 3x + 2y, and whether x < y.
+    let program = super::asm::assemble(
+        "
+            in [x]
+            in [y]
+            mul [x], #3 -> [t]
+            mul [y], #2 -> [u]
+            add [t], [u] -> [t]
+            out [t]
+            lt [x], [y] -> [t]
+            out [t]
+            hlt
+        x:  .data 0
+        y:  .data 0
+        t:  .data 0
+        u:  .data 0
+        ",
+    )
+    .unwrap();
+    let machine = super::Machine::from_mem_spec(&program).unwrap();
+    let mut symbolic = Symbolic::new(&machine.memory.to_vec());
+    symbolic.symbol_input("x");
+    symbolic.symbol_input("y");
+    symbolic.run().unwrap();
+    let outputs: Vec<String> = symbolic.output.iter().map(|o| o.to_string()).collect();
+    assert_eq!(outputs, vec!["(x * 3) + (y * 2)", "x < y"]);
+
+    let sum = &symbolic.output[0];
+    assert_eq!(sum.eval(&[5, 7]), Some(29));
+    assert_eq!(symbolic.solve(sum, 29, &[0..=9, 0..=9]), Some(vec![5, 7]));
+    assert_eq!(symbolic.solve(sum, 28, &[0..=9, 9..=9]), None);
+    // Comparisons aren't linear, so this has to try everything.
+    let less = &symbolic.output[1];
+    assert_eq!(symbolic.solve(less, 1, &[3..=9, 3..=9]), Some(vec![3, 4]));
+    assert_eq!(symbolic.solve(less, 1, &[9..=9, 0..=9]), None);
+}
+
+#[test]
+fn symbolic_control_flow_must_be_concrete() {
+    // Jumps on its input.
+    let mut symbolic = Symbolic::new(&[3, 7, 1005, 7, 0, 99, 0, 0]);
+    symbolic.symbol_input("x");
+    assert_eq!(symbolic.run(), Err(SymbolicError::Jump { pc: 2 }));
+
+    // Writes to wherever `x` says.
+    let mut symbolic = Symbolic::new(&[1101, 1, 2, 0, 99]);
+    symbolic.symbol_at(3, "x").unwrap();
+    assert_eq!(symbolic.run(), Err(SymbolicError::Write { pc: 0 }));
+
+    let mut symbolic = Symbolic::new(&[3, 0, 99]);
+    assert_eq!(
+        symbolic.run(),
+        Err(SymbolicError::Machine(MachineError::MissingInput { pc: 0 }))
+    );
+}
+
+#[test]
+fn symbolic_memory_is_sparse() {
+    // Writes 3 far away, then adds 1101 to whatever's at `x`.
+    let program = [1101, 1, 2, 1_000_000, 4, 1_000_000, 1, 0, 0, 11, 99, 0];
+    let mut symbolic = Symbolic::new(&program);
+    symbolic.symbol_at(7, "x").unwrap();
+    symbolic.run().unwrap();
+    assert_eq!(symbolic.output[0].as_const(), Some(3));
+    assert_eq!(symbolic.memory.allocated_pages(), 2);
+    assert_eq!(symbolic.memory[11].eval(&[3]), Some(1_001_101));
+    assert_eq!(symbolic.memory[11].eval(&[1_000_000]), Some(1104));
+
+    assert!(matches!(
+        symbolic.symbol_at(usize::MAX, "y"),
+        Err(MachineError::MemoryLimit { .. })
+    ));
+}
+
+#[test]
+fn symbolic_expressions_stay_shallow() {
+    // Adds `x` to itself 200,000 times, which would be far too deep to
+    // evaluate or print.
+    let program = super::asm::assemble(
+        "
+        loop:
+            add [acc], [x] -> [acc]
+            add [n], #-1 -> [n]
+            jt [n], #loop
+            hlt
+        acc: .data 0
+        x:   .data 0
+        n:   .data 200000
+        ",
+    )
+    .unwrap();
+    let machine = super::Machine::from_mem_spec(&program).unwrap();
+    let mut symbolic = Symbolic::new(&machine.memory.to_vec());
+    symbolic.symbol_at(13, "x").unwrap();
+    assert_eq!(symbolic.run(), Err(SymbolicError::TooDeep { pc: 0 }));
+    assert_eq!(symbolic.memory[12].depth(), MAX_DEPTH);
+
+    // Even without the bound, dropping a long chain is fine.
+    let x = Symbolic::new(&[]).symbol("x");
+    let mut chain = x.clone();
+    for _ in 0..1_000_000 {
+        chain = add(chain, x.clone());
+    }
+    drop(chain);
+}
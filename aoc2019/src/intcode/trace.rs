@@ -0,0 +1,311 @@
+use super::{ArgMode, Machine, Opcode};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Write<W = isize> {
+    pub addr: usize,
+    pub old: W,
+    pub new: W,
+}
+
+// Everything needed to redo (or undo) one executed instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Entry<W = isize> {
+    pub pc: usize,
+    pub next_pc: usize,
+    pub opcode: Opcode,
+    // From whichever instruction set the machine had.
+    pub mnemonic: &'static str,
+    pub modes: [ArgMode; 3],
+    // The address each operand was actually read from, and what was there.
+    pub reads: Vec<(usize, W)>,
+    pub write: Option<Write<W>>,
+    pub relative_base_change: isize,
+}
+
+// Prints as e.g. `0012: ADD [13]=5 [14]=2 [224]:0->7 rb+3`.
+impl<W: fmt::Display> fmt::Display for Entry<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}: {}", self.pc, self.mnemonic)?;
+        for (addr, value) in &self.reads {
+            write!(f, " [{}]={}", addr, value)?;
+        }
+        if let Some(write) = &self.write {
+            write!(f, " [{}]:{}->{}", write.addr, write.old, write.new)?;
+        }
+        if self.relative_base_change != 0 {
+            write!(f, " rb{:+}", self.relative_base_change)?;
+        }
+        Ok(())
+    }
+}
+
+// Filled in by `Machine::step` whenever `Machine::trace` is set.
+#[derive(Debug, Clone, Default)]
+pub struct Trace<W = isize> {
+    pub entries: Vec<Entry<W>>,
+    // The instruction being executed right now, and the relative base
+    // from before it started.
+    current: Option<(Entry<W>, isize)>,
+}
+
+impl<W> Trace<W> {
+    pub(super) fn begin(
+        &mut self,
+        pc: usize,
+        opcode: Opcode,
+        mnemonic: &'static str,
+        modes: [ArgMode; 3],
+        relative_base: isize,
+    ) {
+        let entry = Entry {
+            pc,
+            next_pc: pc,
+            opcode,
+            mnemonic,
+            modes,
+            reads: Vec::new(),
+            write: None,
+            relative_base_change: 0,
+        };
+        self.current = Some((entry, relative_base));
+    }
+
+    pub(super) fn record_read(&mut self, addr: usize, value: W) {
+        if let Some((entry, _)) = &mut self.current {
+            entry.reads.push((addr, value));
+        }
+    }
+
+    pub(super) fn record_write(&mut self, addr: usize, old: W, new: W) {
+        if let Some((entry, _)) = &mut self.current {
+            entry.write = Some(Write { addr, old, new });
+        }
+    }
+
+    pub(super) fn finish(&mut self, next_pc: usize, relative_base: isize) {
+        if let Some((mut entry, old_base)) = self.current.take() {
+            entry.next_pc = next_pc;
+            entry.relative_base_change = relative_base.wrapping_sub(old_base);
+            self.entries.push(entry);
+        }
+    }
+
+    // The first step at which the two runs were at different instructions,
+    // or `None` if they went exactly the same way.
+    pub fn divergence(&self, other: &Trace<W>) -> Option<usize> {
+        let same = self
+            .entries
+            .iter()
+            .zip(&other.entries)
+            .take_while(|(a, b)| a.pc == b.pc)
+            .count();
+        if same == self.entries.len() && same == other.entries.len() {
+            None
+        } else {
+            Some(same)
+        }
+    }
+}
+
+impl<W: fmt::Display> fmt::Display for Trace<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+// Reconstructs the machine state at any point in a trace without
+// re-executing anything, by applying (or undoing) the recorded effects.
+pub struct Replay<'a> {
+    entries: &'a [Entry],
+    machine: Machine,
+    position: usize,
+}
+
+impl<'a> Replay<'a> {
+    // `initial` has to be the machine as it was when the trace started.
+    pub fn new(mut initial: Machine, trace: &'a Trace) -> Self {
+        initial.trace = None;
+        Replay {
+            entries: &trace.entries,
+            machine: initial,
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // Moves to the state after `step` instructions have executed.
+    // Memory that the real machine grew into stays grown when seeking
+    // backwards, but is otherwise identical.
+    pub fn seek(&mut self, step: usize) -> &Machine {
+        let step = step.min(self.entries.len());
+        while self.position < step {
+            self.forward();
+        }
+        while self.position > step {
+            self.backward();
+        }
+        &self.machine
+    }
+
+    // Whether `entry` was the builtin IN or OUT. Their opcodes are fixed,
+    // but a custom instruction can take one of them over.
+    fn io(&self, entry: &Entry) -> Option<Opcode> {
+        let definition = self.machine.instructions().get(entry.opcode)?;
+        match entry.opcode {
+            Opcode(3..=4) if !definition.is_custom() => Some(entry.opcode),
+            _ => None,
+        }
+    }
+
+    fn forward(&mut self) {
+        let entry = &self.entries[self.position];
+        let io = self.io(entry);
+        let machine = &mut self.machine;
+        // Mirror the memory growth the real machine did while fetching.
+        let arity = (machine.instructions().get(entry.opcode)).map_or(0, |def| def.arity);
+        let touched = entry
+            .reads
+            .iter()
+            .map(|&(addr, _)| addr)
+            .chain(entry.write.map(|write| write.addr))
+            .chain(Some(entry.pc + arity));
+        for addr in touched {
+            if addr >= machine.memory.len() {
+                machine.memory.resize(addr + 1);
+            }
+        }
+        if let Some(write) = entry.write {
+            machine.memory[write.addr] = write.new;
+        }
+        match io {
+            Some(Opcode(3)) => {
+                machine.input.pop_front();
+            }
+            Some(Opcode(4)) => machine.output.push(entry.reads[0].1),
+            _ => (),
+        }
+        machine.relative_base = machine
+            .relative_base
+            .wrapping_add(entry.relative_base_change);
+        machine.pc = entry.next_pc;
+        self.position += 1;
+    }
+
+    fn backward(&mut self) {
+        self.position -= 1;
+        let entry = &self.entries[self.position];
+        let io = self.io(entry);
+        let machine = &mut self.machine;
+        if let Some(write) = entry.write {
+            machine.memory[write.addr] = write.old;
+        }
+        match (io, entry.write) {
+            (Some(Opcode(3)), Some(write)) => machine.input.push_front(write.new),
+            (Some(Opcode(4)), _) => {
+                machine.output.pop();
+            }
+            _ => (),
+        }
+        machine.relative_base = machine
+            .relative_base
+            .wrapping_sub(entry.relative_base_change);
+        machine.pc = entry.pc;
+    }
+}
+
+#[cfg(test)]
+fn traced_run(program: &str, input: &[isize]) -> (Machine, Trace) {
+    let mut machine = Machine::from_mem_spec(program).unwrap();
+    machine.input.extend(input);
+    machine.trace = Some(Trace::default());
+    let initial = machine.clone();
+    machine.run().unwrap();
+    (initial, machine.trace.unwrap())
+}
+
+#[test]
+fn trace_records_effects() {
+    let (_, trace) = traced_run("109,19,204,-19,1101,2,3,5,99", &[]);
+    let lines: Vec<String> = trace.entries.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        lines,
+        [
+            "0000: ARB [1]=19 rb+19",
+            "0002: OUT [0]=109",
+            "0004: ADD [5]=2 [6]=3 [5]:2->5",
+            "0008: HLT",
+        ]
+    );
+}
+
+#[test]
+fn trace_divergence() {
+    let (_, low) = traced_run(super::BIG_TEST, &[3]);
+    let (_, same) = traced_run(super::BIG_TEST, &[3]);
+    let (_, high) = traced_run(super::BIG_TEST, &[8]);
+    assert_eq!(low.divergence(&same), None);
+    // Both runs read the input, compare it to 8 and branch on the result.
+    assert_eq!(low.divergence(&high), Some(3));
+}
+
+#[test]
+fn replay_matches_execution() {
+    let quine = "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99";
+    // Wraps the relative base from the bottom of the range to the top.
+    let wraps = "109,-9223372036854775808,109,-1,99";
+    for &(program, input) in &[(quine, 0), (super::BIG_TEST, 8), (wraps, 0)] {
+        let (initial, trace) = traced_run(program, &[input]);
+        let mut replay = Replay::new(initial.clone(), &trace);
+        let mut fresh = initial.clone();
+        fresh.trace = None;
+        for step in 0..=trace.entries.len() {
+            let state = replay.seek(step);
+            assert_eq!(state.pc, fresh.pc);
+            assert_eq!(state.relative_base, fresh.relative_base);
+            assert_eq!(state.memory, fresh.memory);
+            assert_eq!(state.input, fresh.input);
+            assert_eq!(state.output, fresh.output);
+            assert_eq!(replay.position(), step);
+            fresh.step().unwrap();
+        }
+        // Going backwards gets us back to where we started.
+        let state = replay.seek(0);
+        assert_eq!(state.pc, 0);
+        assert_eq!(
+            &state.memory.to_vec()[..initial.memory.len()],
+            &initial.memory.to_vec()[..]
+        );
+        assert_eq!(state.input, initial.input);
+        assert!(state.output.is_empty());
+    }
+}
+
+#[test]
+fn replay_ignores_custom_io_opcodes() {
+    use super::isa::InstructionSet;
+
+    // Opcode 4 doubles its argument instead of outputting it.
+    let mut instructions = InstructionSet::full();
+    instructions.register(Opcode(4), "DBL", 2, Some(1), |args: &[isize]| {
+        Ok(args[0] * 2)
+    });
+    let mut machine = Machine::from_mem_spec("4,4,5,99,21,0").unwrap();
+    machine.set_instructions(instructions);
+    machine.trace = Some(Trace::default());
+    let initial = machine.clone();
+    machine.run().unwrap();
+
+    let trace = machine.trace.unwrap();
+    let mut replay = Replay::new(initial, &trace);
+    let state = replay.seek(1);
+    assert_eq!(state.memory[5], 42);
+    assert!(state.output.is_empty());
+    assert_eq!(replay.seek(0).memory[5], 0);
+}
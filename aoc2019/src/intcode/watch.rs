@@ -0,0 +1,190 @@
+// Watchpoints, for finding out which instruction is messing with a
+// particular bit of memory.
+use super::{Machine, MachineError, Step, Stop, Word};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Which accesses a watchpoint fires on.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Watch {
+    Reads,
+    Writes,
+    Both,
+}
+
+impl Watch {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            Watch::Reads => access == Access::Read,
+            Watch::Writes => access == Access::Write,
+            Watch::Both => true,
+        }
+    }
+}
+
+// `old` and `new` are the same for reads.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hit<W = isize> {
+    pub pc: usize,
+    pub addr: usize,
+    pub access: Access,
+    pub old: W,
+    pub new: W,
+}
+
+// Prints as e.g. `0008: wrote [12] 3->5` or `0004: read [12]=3`.
+impl<W: fmt::Display> fmt::Display for Hit<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(f, "{:04}: read [{}]={}", self.pc, self.addr, self.new),
+            Access::Write => write!(
+                f,
+                "{:04}: wrote [{}] {}->{}",
+                self.pc, self.addr, self.old, self.new
+            ),
+        }
+    }
+}
+
+// Filled in by the machine whenever `Machine::watchpoints` is set.
+#[derive(Debug, Clone, Default)]
+pub struct Watchpoints<W = isize> {
+    watches: Vec<(Range<usize>, Watch)>,
+    // Hits nobody has looked at yet, oldest first.
+    pub hits: Vec<Hit<W>>,
+}
+
+impl<W: Clone> Watchpoints<W> {
+    pub fn watch(&mut self, range: Range<usize>, watch: Watch) {
+        self.watches.push((range, watch));
+    }
+
+    // Removes every watchpoint covering `addr`.
+    pub fn unwatch(&mut self, addr: usize) {
+        self.watches.retain(|(range, _)| !range.contains(&addr));
+    }
+
+    pub(super) fn record(&mut self, pc: usize, addr: usize, access: Access, old: &W, new: &W) {
+        let watched = self
+            .watches
+            .iter()
+            .any(|(range, watch)| range.contains(&addr) && watch.matches(access));
+        if watched {
+            self.hits.push(Hit {
+                pc,
+                addr,
+                access,
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+    }
+}
+
+impl<W: Word> Machine<W> {
+    pub fn watch(&mut self, range: Range<usize>, watch: Watch) {
+        self.watchpoints
+            .get_or_insert_with(Watchpoints::default)
+            .watch(range, watch);
+    }
+
+    // Runs like `run`, handing each watchpoint hit to `on_hit`, which
+    // returns whether to pause once the instruction is done.
+    pub fn run_watching(
+        &mut self,
+        mut on_hit: impl FnMut(&Hit<W>) -> bool,
+    ) -> Result<Stop, MachineError> {
+        use Step::*;
+        loop {
+            let step = self.step()?;
+            let mut pause = false;
+            if let Some(watchpoints) = &mut self.watchpoints {
+                for hit in watchpoints.hits.drain(..) {
+                    pause |= on_hit(&hit);
+                }
+            }
+            match step {
+                Output(_) | Continue if pause => return Ok(Stop::Paused { pc: self.pc }),
+                Output(_) | Continue => continue,
+                Halt => return Ok(Stop::Halted),
+                Input => return Err(MachineError::MissingInput { pc: self.pc }),
+            }
+        }
+    }
+}
+
+#[test]
+fn watchpoints_catch_the_culprit() {
+    use super::asm::assemble;
+
+    let program = assemble(
+        "
+            add #1, #2 -> [x]
+            mul [x], #10 -> [y]
+            add #5, #0 -> [x]
+            hlt
+        x:  .data 0
+        y:  .data 0
+        ",
+    )
+    .unwrap();
+    let x = 13;
+
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    machine.watch(x..x + 1, Watch::Writes);
+    let mut hits = Vec::new();
+    let stop = machine.run_watching(|hit| {
+        hits.push(hit.to_string());
+        false
+    });
+    assert_eq!(stop, Ok(Stop::Halted));
+    assert_eq!(hits, vec!["0000: wrote [13] 0->3", "0008: wrote [13] 3->5"]);
+
+    // Pausing leaves the machine just after the offending instruction.
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    machine.watch(x..x + 1, Watch::Both);
+    let mut pcs = Vec::new();
+    loop {
+        match machine.run_watching(|hit| {
+            pcs.push(hit.pc);
+            true
+        }) {
+            Ok(Stop::Paused { .. }) => continue,
+            stop => {
+                assert_eq!(stop, Ok(Stop::Halted));
+                break;
+            }
+        }
+    }
+    assert_eq!(pcs, vec![0, 4, 8]);
+}
+
+#[test]
+fn read_watchpoints_ignore_writes() {
+    use super::asm::assemble;
+
+    let program = assemble(
+        "
+            add #1, #2 -> [x]
+            out [x]
+            hlt
+        x:  .data 0
+        ",
+    )
+    .unwrap();
+    let mut machine = Machine::from_mem_spec(&program).unwrap();
+    machine.watch(7..8, Watch::Reads);
+    let mut hits = Vec::new();
+    let stop = machine.run_watching(|hit| {
+        hits.push(hit.to_string());
+        false
+    });
+    assert_eq!(stop, Ok(Stop::Halted));
+    assert_eq!(hits, vec!["0004: read [7]=3"]);
+}
@@ -0,0 +1,84 @@
+// What a machine's memory is made of.
+//
+// Addresses, opcodes and relative base adjustments all go through `isize`.
+// Anything that doesn't fit saturates, so it faults as a bad address rather
+// than quietly wrapping around to a good one.
+use num::bigint::{BigInt, Sign};
+use num::ToPrimitive;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+// `Default` has to be zero.
+pub trait Word: Clone + Default + Ord + Hash + fmt::Debug + fmt::Display + FromStr {
+    fn from_isize(value: isize) -> Self;
+    fn saturating_isize(&self) -> isize;
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    fn checked_mul(&self, other: &Self) -> Option<Self>;
+    fn wrapping_add(&self, other: &Self) -> Self;
+    fn wrapping_mul(&self, other: &Self) -> Self;
+}
+
+macro_rules! primitive_word {
+    ($($t:ty),*) => {$(
+        impl Word for $t {
+            fn from_isize(value: isize) -> Self {
+                value as $t
+            }
+
+            fn saturating_isize(&self) -> isize {
+                isize::try_from(*self).unwrap_or(if *self < 0 { isize::MIN } else { isize::MAX })
+            }
+
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_add(*self, *other)
+            }
+
+            fn checked_mul(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_mul(*self, *other)
+            }
+
+            fn wrapping_add(&self, other: &Self) -> Self {
+                <$t>::wrapping_add(*self, *other)
+            }
+
+            fn wrapping_mul(&self, other: &Self) -> Self {
+                <$t>::wrapping_mul(*self, *other)
+            }
+        }
+    )*};
+}
+
+primitive_word!(isize, i64, i128);
+
+// Never overflows, so checked and wrapping arithmetic are the same thing.
+impl Word for BigInt {
+    fn from_isize(value: isize) -> Self {
+        BigInt::from(value)
+    }
+
+    fn saturating_isize(&self) -> isize {
+        ToPrimitive::to_isize(self).unwrap_or(if self.sign() == Sign::Minus {
+            isize::MIN
+        } else {
+            isize::MAX
+        })
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Some(self * other)
+    }
+
+    fn wrapping_add(&self, other: &Self) -> Self {
+        self + other
+    }
+
+    fn wrapping_mul(&self, other: &Self) -> Self {
+        self * other
+    }
+}
@@ -14,6 +14,24 @@ mod day9;
 mod day10;
 mod day11;
 
+use intcode::debugger::Debugger;
+use intcode::Machine;
+use std::io;
+use std::process::exit;
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().collect();
+    match args.as_slice() {
+        [_, command, path] if command == "debug" => {
+            let machine = Machine::from_file(path).unwrap_or_else(|err| {
+                eprintln!("could not load {}", err);
+                exit(1)
+            });
+            let stdin = io::stdin();
+            Debugger::new(machine)
+                .repl(stdin.lock(), io::stdout())
+                .unwrap();
+        }
+        _ => println!("usage: aoc2019 debug <program>"),
+    }
 }
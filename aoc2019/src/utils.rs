@@ -3,3 +3,19 @@ pub mod types {
     pub type Vector = euclid::Vector2D<isize, ()>;
     pub type BoundingBox = euclid::Box2D<isize, ()>;
 }
+
+// Runs `f` a few times and prints the best time, since the slow runs are
+// mostly noise. Benchmarks are ignored tests named `bench_*`, so run them
+// with `cargo test --release bench -- --ignored --nocapture`.
+#[cfg(test)]
+pub fn bench(name: &str, runs: usize, mut f: impl FnMut()) {
+    let best = (0..runs)
+        .map(|_| {
+            let start = std::time::Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    println!("{}: {:?}", name, best);
+}